@@ -1,36 +1,620 @@
-use std::collections::HashMap;
-use teloxide::types::{ChatId, Message};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant};
+use teloxide::prelude::*;
+use teloxide::types::{Chat, ChatId, Message, User, UserId};
 
 use crate::config::{AllowedChat, Config};
+use crate::language::Language;
+use crate::storage::Storage;
 
+/// How long the administrators of a chat are cached for.
+const ADMINS_TTL: Duration = Duration::from_secs(10 * 60);
+/// How long the membership of a user in the chats whose members may use the
+/// bot in private chats is cached for.
+const DM_MEMBERS_TTL: Duration = Duration::from_secs(10 * 60);
+/// How often an unauthorized chat is told that the bot is private.
+const REJECTION_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+/// How long the bot stays in an unauthorized group it was just added to,
+/// for someone to send `/activate` with an invite code.
+pub const ACTIVATION_GRACE: Duration = Duration::from_secs(15 * 60);
+
+/// What a user may do with the bot, from least to most privileged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// Anyone in an allowed chat.
+    Member,
+    /// Administrators of a group, or the user in a private chat.
+    Admin,
+    /// The bot owners from the config.
+    Owner,
+}
+
+/// Whether a chat is allowed by the config or was allowed at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllowedBy {
+    Config,
+    Runtime,
+}
+
+/// Chats and users allowed to use the bot and the users owning it.
+///
+/// The allow-list starts out as the config's `allowed_chats`, overridden by
+/// the chats allowed or denied with `/allow` and `/deny`. Changes are
+/// persisted and apply to the running dispatcher immediately.
+///
+/// Private chats are also allowed for the owners, the configured
+/// `allowed_users` and the members of chats with `members_may_dm` set.
+///
+/// A config reload builds a new `Auth` with `reload`, which shares the
+/// runtime state with the current one.
 pub struct Auth {
-    allowed_chats: HashMap<ChatId, AllowedChat>,
+    storage: Storage,
+    owners: HashSet<UserId>,
+    allowed_user_ids: HashSet<UserId>,
+    allowed_usernames: HashSet<String>,
+    config_chats: HashMap<ChatId, AllowedChat>,
+    /// Checked against the config, so cleared on reload.
+    dm_members: Mutex<HashMap<UserId, (Instant, bool)>>,
+    /// The daily and monthly quotas of the chats without their own.
+    default_char_quotas: (Option<u64>, Option<u64>),
+    runtime: Arc<Runtime>,
+}
+
+/// The state of `Auth` that doesn't come from the config. It's shared with
+/// the `Auth` built on reload, so that a chat allowed by a handler still
+/// holding the old one isn't lost.
+struct Runtime {
+    /// The chats allowed (`true`) or denied with `/allow` and `/deny`, with
+    /// their names.
+    overrides: RwLock<HashMap<ChatId, (String, bool)>>,
+    admins: Mutex<HashMap<ChatId, (Instant, HashSet<UserId>)>>,
+    rejections: Mutex<HashMap<ChatId, Instant>>,
+    /// When the bot was added to unauthorized groups.
+    joins: Mutex<HashMap<ChatId, Instant>>,
+}
+
+/// A human readable name of the chat: its title, or the username or first
+/// name of the user in a private chat.
+pub fn chat_name(chat: &Chat) -> &str {
+    chat.title()
+        .or_else(|| chat.username())
+        .or_else(|| chat.first_name())
+        .unwrap_or_default()
+}
+
+fn normalize_username(username: &str) -> String {
+    username.trim_start_matches('@').to_lowercase()
 }
 
 impl Auth {
-    pub fn new(config: &Config) -> Self {
-        Self {
-            allowed_chats: config
-                .allowed_chats
-                .clone()
-                .into_iter()
-                .map(|chat| (ChatId(chat.id), chat))
+    pub fn new(config: &Config, storage: Storage) -> crate::Result<Self> {
+        let overrides = storage
+            .allowed_chats()
+            .list()?
+            .into_iter()
+            .map(|access| {
+                (ChatId(access.chat_id), (access.name, access.allowed))
+            })
+            .collect();
+        let runtime = Arc::new(Runtime {
+            overrides: RwLock::new(overrides),
+            admins: Mutex::new(HashMap::new()),
+            rejections: Mutex::new(HashMap::new()),
+            joins: Mutex::new(HashMap::new()),
+        });
+        Ok(Self::with_runtime(config, storage, runtime))
+    }
+
+    /// Builds the `Auth` of a reloaded config, keeping the chats allowed or
+    /// denied at runtime, the cached admins, and the rejected and joined
+    /// chats.
+    pub fn reload(&self, config: &Config) -> Self {
+        Self::with_runtime(config, self.storage.clone(), self.runtime.clone())
+    }
+
+    fn with_runtime(
+        config: &Config,
+        storage: Storage,
+        runtime: Arc<Runtime>,
+    ) -> Self {
+        let config_chats = config
+            .allowed_chats
+            .clone()
+            .into_iter()
+            .map(|chat| (ChatId(chat.id), chat))
+            .collect();
+        let auth = Self {
+            storage,
+            owners: config.owners.iter().map(|id| UserId(*id)).collect(),
+            allowed_user_ids: config
+                .allowed_users
+                .iter()
+                .filter_map(|user| user.id.map(UserId))
+                .collect(),
+            allowed_usernames: config
+                .allowed_users
+                .iter()
+                .filter_map(|user| user.username.as_deref())
+                .map(normalize_username)
                 .collect(),
+            config_chats,
+            dm_members: Mutex::new(HashMap::new()),
+            default_char_quotas: (
+                Some(config.default_daily_char_quota).filter(|q| *q > 0),
+                Some(config.default_monthly_char_quota).filter(|q| *q > 0),
+            ),
+            runtime,
+        };
+        let allowed = auth.list_chats();
+        log::info!(
+            "Allowed Chat IDs: {:?}",
+            allowed.iter().map(|(chat, _)| chat.id).collect::<Vec<_>>()
+        );
+        auth
+    }
+
+    fn overrides(
+        &self,
+    ) -> RwLockReadGuard<'_, HashMap<ChatId, (String, bool)>> {
+        self.runtime.overrides.read().expect("allow-list poisoned")
+    }
+
+    /// The chat from the config, unless denied at runtime, or the chat
+    /// allowed at runtime.
+    fn resolve(
+        &self,
+        chat_id: ChatId,
+        overridden: Option<&(String, bool)>,
+    ) -> Option<(AllowedChat, AllowedBy)> {
+        match (overridden, self.config_chats.get(&chat_id)) {
+            (Some((_, false)), _) | (None, None) => None,
+            (_, Some(chat)) => Some((chat.clone(), AllowedBy::Config)),
+            (Some((name, true)), None) => Some((
+                AllowedChat::new(chat_id.0, name.clone()),
+                AllowedBy::Runtime,
+            )),
+        }
+    }
+
+    fn chat(&self, chat_id: &ChatId) -> Option<AllowedChat> {
+        self.resolve(*chat_id, self.overrides().get(chat_id))
+            .map(|(chat, _)| chat)
+    }
+
+    pub fn chat_is_allowed(&self, chat_id: &ChatId) -> bool {
+        match self.overrides().get(chat_id) {
+            Some((_, allowed)) => *allowed,
+            None => self.config_chats.contains_key(chat_id),
         }
     }
 
-    pub fn message_is_authorized(&self, message: Message) -> bool {
-        let chat_id = message.chat.id;
-        let is_authorized = self.allowed_chats.contains_key(&chat_id);
+    pub async fn message_is_authorized(
+        &self,
+        bot: &Bot,
+        message: &Message,
+    ) -> bool {
+        self.is_authorized(bot, &message.chat, message.from()).await
+    }
+
+    /// Whether the user may use the bot in the chat.
+    pub async fn is_authorized(
+        &self,
+        bot: &Bot,
+        chat: &Chat,
+        user: Option<&User>,
+    ) -> bool {
+        let is_authorized = self.chat_is_allowed(&chat.id)
+            || (chat.is_private() && self.user_may_dm(bot, user).await);
         if !is_authorized {
-            log::warn!("Chat [{}] is not authorized", &chat_id.0);
+            log::warn!("Chat [{}] is not authorized", &chat.id.0);
         }
         is_authorized
     }
 
+    /// Whether the user may use the bot in a private chat.
+    async fn user_may_dm(&self, bot: &Bot, user: Option<&User>) -> bool {
+        let user = match user {
+            Some(user) => user,
+            None => return false,
+        };
+        if self.is_owner(user.id) || self.allowed_user_ids.contains(&user.id) {
+            return true;
+        }
+        let username_allowed = user.username.as_deref().is_some_and(|name| {
+            self.allowed_usernames.contains(&normalize_username(name))
+        });
+        if username_allowed {
+            return true;
+        }
+
+        let cached = self
+            .dm_members
+            .lock()
+            .expect("members cache poisoned")
+            .get(&user.id)
+            .filter(|(checked_at, _)| checked_at.elapsed() < DM_MEMBERS_TTL)
+            .map(|(_, is_member)| *is_member);
+        if let Some(is_member) = cached {
+            return is_member;
+        }
+
+        let dm_chats = self
+            .list_chats()
+            .into_iter()
+            .filter(|(chat, _)| chat.members_may_dm)
+            .map(|(chat, _)| ChatId(chat.id))
+            .collect::<Vec<_>>();
+        let mut is_member = false;
+        let mut is_known = true;
+        for chat_id in dm_chats {
+            match bot.get_chat_member(chat_id, user.id).await {
+                Ok(member) if member.is_present() => {
+                    is_member = true;
+                    break;
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    log::warn!(
+                        "Cannot check membership of [{}] in chat [{}]: {err}",
+                        user.id,
+                        chat_id
+                    );
+                    is_known = false;
+                }
+            }
+        }
+        // A failed check is tried again next time rather than locking the
+        // user out for the whole TTL.
+        if is_member || is_known {
+            self.dm_members
+                .lock()
+                .expect("members cache poisoned")
+                .insert(user.id, (Instant::now(), is_member));
+        }
+        is_member
+    }
+
+    /// Records that the unauthorized chat was turned away. Returns whether
+    /// it's the first time in a while, so the chat isn't flooded with
+    /// rejections.
+    pub fn record_rejection(&self, chat_id: ChatId) -> bool {
+        self.record_rejection_at(chat_id, Instant::now())
+    }
+
+    fn record_rejection_at(&self, chat_id: ChatId, now: Instant) -> bool {
+        let is_recent = |rejected_at: &Instant| {
+            now.saturating_duration_since(*rejected_at) < REJECTION_INTERVAL
+        };
+        let mut rejections =
+            self.runtime.rejections.lock().expect("rejections poisoned");
+        let is_first = !rejections.get(&chat_id).is_some_and(is_recent);
+        if is_first {
+            // Only chats turned away recently need to be remembered.
+            rejections.retain(|_, rejected_at| is_recent(rejected_at));
+            rejections.insert(chat_id, now);
+        }
+        is_first
+    }
+
+    /// Records that the bot was added to the unauthorized group.
+    pub fn record_join(&self, chat_id: ChatId) {
+        self.record_join_at(chat_id, Instant::now())
+    }
+
+    fn record_join_at(&self, chat_id: ChatId, now: Instant) {
+        let mut joins = self.runtime.joins.lock().expect("joins poisoned");
+        joins.retain(|_, joined_at| {
+            now.saturating_duration_since(*joined_at) < ACTIVATION_GRACE
+        });
+        joins.insert(chat_id, now);
+    }
+
+    /// Whether the bot was added to the unauthorized group recently enough
+    /// to wait for `/activate` in it.
+    pub fn awaits_activation(&self, chat_id: ChatId) -> bool {
+        self.awaits_activation_at(chat_id, Instant::now())
+    }
+
+    fn awaits_activation_at(&self, chat_id: ChatId, now: Instant) -> bool {
+        let joins = self.runtime.joins.lock().expect("joins poisoned");
+        joins.get(&chat_id).is_some_and(|joined_at| {
+            now.saturating_duration_since(*joined_at) < ACTIVATION_GRACE
+        })
+    }
+
+    pub fn is_owner(&self, user_id: UserId) -> bool {
+        self.owners.contains(&user_id)
+    }
+
+    pub fn owners(&self) -> impl Iterator<Item = &UserId> {
+        self.owners.iter()
+    }
+
+    /// The role of the sender of `message` in its chat. Group administrators
+    /// are fetched from Telegram and cached for a while.
+    pub async fn role(
+        &self,
+        bot: &Bot,
+        message: &Message,
+    ) -> crate::Result<Role> {
+        self.role_in(bot, &message.chat, message.from()).await
+    }
+
+    /// The role of the user in the chat.
+    pub async fn role_in(
+        &self,
+        bot: &Bot,
+        chat: &Chat,
+        user: Option<&User>,
+    ) -> crate::Result<Role> {
+        let user = match user {
+            Some(user) => user,
+            None => return Ok(Role::Member),
+        };
+        if self.is_owner(user.id) {
+            return Ok(Role::Owner);
+        }
+        if chat.is_private() {
+            return Ok(Role::Admin);
+        }
+
+        let admins = self.chat_admins(bot, chat.id).await?;
+        match admins.contains(&user.id) {
+            true => Ok(Role::Admin),
+            false => Ok(Role::Member),
+        }
+    }
+
+    /// Whether the sender of `message` has at least the `required` role.
+    pub async fn has_role(
+        &self,
+        bot: &Bot,
+        message: &Message,
+        required: Role,
+    ) -> bool {
+        if required == Role::Member {
+            return true;
+        }
+        match self.role(bot, message).await {
+            Ok(role) => role >= required,
+            Err(err) => {
+                log::error!("Cannot get the role of a user: {err}");
+                false
+            }
+        }
+    }
+
+    async fn chat_admins(
+        &self,
+        bot: &Bot,
+        chat_id: ChatId,
+    ) -> crate::Result<HashSet<UserId>> {
+        let cached = self
+            .runtime
+            .admins
+            .lock()
+            .expect("admins cache poisoned")
+            .get(&chat_id)
+            .filter(|(fetched_at, _)| fetched_at.elapsed() < ADMINS_TTL)
+            .map(|(_, admins)| admins.clone());
+        if let Some(admins) = cached {
+            return Ok(admins);
+        }
+
+        let admins = bot
+            .get_chat_administrators(chat_id)
+            .await?
+            .into_iter()
+            .map(|member| member.user.id)
+            .collect::<HashSet<_>>();
+        log::debug!("Fetched {} admins of chat [{}]", admins.len(), chat_id);
+        self.runtime
+            .admins
+            .lock()
+            .expect("admins cache poisoned")
+            .insert(chat_id, (Instant::now(), admins.clone()));
+        Ok(admins)
+    }
+
+    /// Allows the chat and persists the change.
+    pub fn allow(
+        &self,
+        chat_id: ChatId,
+        name: &str,
+        by: UserId,
+    ) -> crate::Result<()> {
+        self.storage.allowed_chats().set(chat_id, name, true, by)?;
+        self.runtime
+            .overrides
+            .write()
+            .expect("allow-list poisoned")
+            .insert(chat_id, (name.to_string(), true));
+        log::info!("Chat [{}] \"{}\" allowed by [{}]", chat_id, name, by);
+        Ok(())
+    }
+
+    /// Denies the chat, even if allowed in the config, and persists the
+    /// change. Returns the chat's name if it was allowed.
+    pub fn deny(
+        &self,
+        chat_id: ChatId,
+        by: UserId,
+    ) -> crate::Result<Option<String>> {
+        let name = self.get_chat_name(&chat_id);
+        let stored_name = name.clone().unwrap_or_default();
+        self.storage
+            .allowed_chats()
+            .set(chat_id, &stored_name, false, by)?;
+        self.runtime
+            .overrides
+            .write()
+            .expect("allow-list poisoned")
+            .insert(chat_id, (stored_name, false));
+        log::info!("Chat [{}] denied by [{}]", chat_id, by);
+        Ok(name)
+    }
+
+    /// The allowed chats, sorted by id.
+    pub fn list_chats(&self) -> Vec<(AllowedChat, AllowedBy)> {
+        let overrides = self.overrides();
+        let mut chats = self
+            .config_chats
+            .keys()
+            .filter(|chat_id| !overrides.contains_key(chat_id))
+            .filter_map(|chat_id| self.resolve(*chat_id, None))
+            .chain(overrides.iter().filter_map(|(chat_id, overridden)| {
+                self.resolve(*chat_id, Some(overridden))
+            }))
+            .collect::<Vec<_>>();
+        chats.sort_by_key(|(chat, _)| chat.id);
+        chats
+    }
+
     pub fn get_chat_name(&self, chat_id: &ChatId) -> Option<String> {
-        self.allowed_chats
-            .get(chat_id)
-            .map(|chat| chat.name.clone())
+        self.chat(chat_id).map(|chat| chat.name)
+    }
+
+    pub fn get_ui_language(&self, chat_id: &ChatId) -> Option<Language> {
+        self.chat(chat_id)
+            .and_then(|chat| chat.ui_language)
+            .as_deref()
+            .and_then(Language::parse_code)
+    }
+
+    /// The daily and monthly character quotas of the chat, if any: those
+    /// of its entry in the config, else the defaults, which also apply to
+    /// the chats allowed at runtime and to private chats.
+    pub fn char_quotas(&self, chat_id: &ChatId) -> (Option<u64>, Option<u64>) {
+        match self.resolve(*chat_id, self.overrides().get(chat_id)) {
+            Some((chat, AllowedBy::Config)) => {
+                (chat.daily_char_quota, chat.monthly_char_quota)
+            }
+            _ => self.default_char_quotas,
+        }
+    }
+
+    pub fn romanize_by_default(&self, chat_id: &ChatId) -> bool {
+        self.chat(chat_id).is_some_and(|chat| chat.romanize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(allowed_chats: Vec<AllowedChat>) -> Config {
+        Config::new(
+            String::new().into(),
+            String::new().into(),
+            allowed_chats,
+            None,
+            None,
+            false,
+        )
+    }
+
+    fn auth() -> Auth {
+        let config = config(vec![AllowedChat::new(-1, "team".to_string())]);
+        Auth::new(&config, Storage::in_memory().unwrap()).unwrap()
+    }
+
+    fn chat_ids(auth: &Auth) -> Vec<i64> {
+        auth.list_chats().iter().map(|(chat, _)| chat.id).collect()
+    }
+
+    #[test]
+    fn overrides_the_config_chats() {
+        let auth = auth();
+        auth.allow(ChatId(-2), "other", UserId(1)).unwrap();
+        assert_eq!(chat_ids(&auth), [-2, -1]);
+        assert_eq!(auth.list_chats()[0].1, AllowedBy::Runtime);
+
+        auth.deny(ChatId(-1), UserId(1)).unwrap();
+        assert!(!auth.chat_is_allowed(&ChatId(-1)));
+        assert_eq!(chat_ids(&auth), [-2]);
+
+        // Allowing a config chat again brings back its settings.
+        auth.allow(ChatId(-1), "renamed", UserId(1)).unwrap();
+        assert_eq!(auth.get_chat_name(&ChatId(-1)).unwrap(), "team");
+    }
+
+    #[test]
+    fn loads_the_overrides_from_the_database() {
+        let auth = auth();
+        auth.allow(ChatId(-2), "other", UserId(1)).unwrap();
+        auth.deny(ChatId(-1), UserId(1)).unwrap();
+        let loaded = Auth::new(&config(vec![]), auth.storage.clone()).unwrap();
+        assert_eq!(chat_ids(&loaded), [-2]);
+    }
+
+    #[test]
+    fn keeps_the_runtime_state_on_reload() {
+        let old = auth();
+        assert!(old.record_rejection(ChatId(-3)));
+        let new =
+            old.reload(&config(vec![AllowedChat::new(-4, "new".to_string())]));
+        assert_eq!(chat_ids(&new), [-4]);
+        assert!(!new.record_rejection(ChatId(-3)));
+
+        // A chat allowed by a handler still holding the old `Auth`.
+        old.allow(ChatId(-2), "other", UserId(1)).unwrap();
+        assert!(new.chat_is_allowed(&ChatId(-2)));
+    }
+
+    #[test]
+    fn rejects_chats_once_in_a_while() {
+        let auth = auth();
+        assert!(auth.record_rejection(ChatId(-2)));
+        assert!(!auth.record_rejection(ChatId(-2)));
+        assert!(auth.record_rejection(ChatId(-3)));
+    }
+
+    #[test]
+    fn forgets_expired_rejections() {
+        let auth = auth();
+        let now = Instant::now();
+        assert!(auth.record_rejection_at(ChatId(-2), now));
+
+        let later = now + REJECTION_INTERVAL;
+        assert!(auth.record_rejection_at(ChatId(-3), later));
+        let rejections = auth.runtime.rejections.lock().unwrap();
+        assert!(!rejections.contains_key(&ChatId(-2)));
+        assert!(rejections.contains_key(&ChatId(-3)));
+    }
+
+    #[test]
+    fn awaits_activation_for_a_while_after_joining() {
+        let auth = auth();
+        let now = Instant::now();
+        auth.record_join_at(ChatId(-2), now);
+        assert!(auth.awaits_activation_at(ChatId(-2), now));
+        assert!(!auth.awaits_activation_at(ChatId(-3), now));
+
+        let later = now + ACTIVATION_GRACE;
+        assert!(!auth.awaits_activation_at(ChatId(-2), later));
+        auth.record_join_at(ChatId(-3), later);
+        let joins = auth.runtime.joins.lock().unwrap();
+        assert!(!joins.contains_key(&ChatId(-2)));
+    }
+
+    #[test]
+    fn chats_without_a_config_entry_get_the_default_quotas() {
+        let mut team = AllowedChat::new(-1, "team".to_string());
+        team.daily_char_quota = Some(500);
+        let config =
+            config(vec![team, AllowedChat::new(-2, "other".to_string())])
+                .set_default_char_quotas(100, 1000);
+        let auth = Auth::new(&config, Storage::in_memory().unwrap()).unwrap();
+        auth.allow(ChatId(-3), "new", UserId(1)).unwrap();
+
+        assert_eq!(auth.char_quotas(&ChatId(-1)), (Some(500), None));
+        assert_eq!(auth.char_quotas(&ChatId(-2)), (None, None));
+        assert_eq!(auth.char_quotas(&ChatId(-3)), (Some(100), Some(1000)));
+        assert_eq!(auth.char_quotas(&ChatId(7)), (Some(100), Some(1000)));
+
+        let config = config.set_default_char_quotas(0, 0);
+        let auth = auth.reload(&config);
+        assert_eq!(auth.char_quotas(&ChatId(-3)), (None, None));
     }
 }
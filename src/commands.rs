@@ -1,80 +1,62 @@
+use chrono::{TimeZone, Utc};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
 use std::sync::Arc;
+use std::time::Duration;
 
 use teloxide::prelude::*;
+use teloxide::types::{BotCommand, Me, MessageId, User};
 use teloxide::utils::command::BotCommands;
+use teloxide::{ApiError, RequestError};
 
-use crate::{Auth, GoogleCloudClient};
+use crate::auth::{chat_name, AllowedBy, Role};
+use crate::i18n::{ui_language, ui_language_for, Catalog};
+use crate::language::Language;
+use crate::limits::{Exceeded, Limits, Purpose};
+use crate::romanize;
+use crate::settings::{settings_menu, OutputFormat, Settings};
+use crate::storage::{this_month, HistoryEntry, Source, Storage};
+use crate::usage::{parse_month, UsageReport};
+use crate::{Auth, Config, GoogleCloudClient, State};
 
-#[derive(Debug, Clone)]
-enum Language {
-    English,
-    German,
-    French,
-    Spanish,
-    Russian,
-    Korean,
+/// Target of a translation as written after the command, e.g. `en`, or
+/// `en+rom` to also get the romanized source text.
+#[derive(Debug, Clone, Copy)]
+struct Target {
+    lang: Language,
+    romanize: bool,
 }
 
-impl Language {
-    pub fn code(&self) -> String {
-        match self {
-            Language::English => "en",
-            Language::German => "de",
-            Language::French => "fr",
-            Language::Spanish => "es",
-            Language::Russian => "ru",
-            Language::Korean => "ko",
-        }
-        .to_owned()
+impl Target {
+    fn parse(token: &str) -> Option<Self> {
+        let (code, romanize) = match token.split_once('+') {
+            Some((code, "rom")) => (code, true),
+            Some(_) => return None,
+            None => (token, false),
+        };
+        Language::parse_code(code).map(|lang| Target { lang, romanize })
     }
 
-    pub fn name(&self) -> String {
-        match self {
-            Language::English => "english",
-            Language::German => "german",
-            Language::French => "french",
-            Language::Spanish => "spanish",
-            Language::Russian => "russian",
-            Language::Korean => "korean",
+    fn code(&self) -> String {
+        match self.romanize {
+            true => format!("{}+rom", self.lang.code()),
+            false => self.lang.code(),
         }
-        .to_owned()
-    }
-
-    pub fn emoji(&self) -> String {
-        match self {
-            Language::English => "🇬🇧",
-            Language::German => "🇩🇪",
-            Language::French => "🇫🇷",
-            Language::Spanish => "🇪🇸",
-            Language::Russian => "🇷🇺",
-            Language::Korean => "🇰🇷",
-        }
-        .to_owned()
-    }
-
-    pub fn parse_code(code: &str) -> Option<Self> {
-        let lang = match code {
-            "en" => Some(Language::English),
-            "de" => Some(Language::German),
-            "fr" => Some(Language::French),
-            "es" => Some(Language::Spanish),
-            "ru" => Some(Language::Russian),
-            "ko" => Some(Language::Korean),
-            _ => None,
-        };
-        log::debug!("{} => {:?}", code, lang);
-        lang
     }
 }
 
-fn parse_command_text(cmd_text: &str) -> (Option<Language>, Option<String>) {
-    let maybe_code = &cmd_text
-        .get(0..std::cmp::min(3, cmd_text.len()))
-        .map(|s| s.trim());
+/// Splits the arguments of `/translate` at the first whitespace into the
+/// target and the text, so that targets can be longer than a language code,
+/// as in `en+rom`. The text may also start on the next line.
+fn parse_command_text(cmd_text: &str) -> (Option<Target>, Option<String>) {
+    let cmd_text = cmd_text.trim();
+    let (token, text) = cmd_text
+        .split_once(char::is_whitespace)
+        .unwrap_or((cmd_text, ""));
 
-    let lang = maybe_code.and_then(Language::parse_code);
-    let text = cmd_text.trim().get(3..).map(|s| s.to_string());
-    (lang, text)
+    let target = Target::parse(token);
+    let text = Some(text.trim().to_string()).filter(|text| !text.is_empty());
+    (target, text)
 }
 
 #[derive(BotCommands, Clone, Debug)]
@@ -92,15 +74,515 @@ pub enum Command {
     Translate(String),
     #[command(description = "shortcut for /translate.")]
     T(String),
+    #[command(description = "reply to one of my translations to delete it.")]
+    Undo,
+    #[command(
+        description = "show how much of the translation budget is left."
+    )]
+    Quota,
+    #[command(description = "search the translations kept in this chat.")]
+    History(String),
+    #[command(description = "delete your translations from the history.")]
+    Forget,
+    #[command(description = "choose the language I talk to you in, e.g. \
+            `/language de`.")]
+    Language(String),
+    #[command(description = "change the settings of this chat.")]
+    Settings,
+    #[command(description = "allow a chat: `/allow <chat_id> <name>`.")]
+    Allow(String),
+    #[command(description = "deny a chat: `/deny <chat_id>`.")]
+    Deny(String),
+    #[command(description = "list the allowed chats.")]
+    Chats,
+    #[command(description = "create an invite code, valid for e.g. `7d`.")]
+    Invite(String),
+    #[command(description = "allow this chat with an invite code.")]
+    Activate(String),
+    #[command(description = "show the translated characters and their \
+            estimated cost for a month, e.g. `2022-11`.")]
+    Usage(String),
+}
+
+impl Command {
+    /// The role a user needs to use the command.
+    pub fn required_role(&self) -> Role {
+        match self {
+            Command::Help
+            | Command::Translate(_)
+            | Command::T(_)
+            | Command::Undo
+            | Command::Quota
+            | Command::History(_)
+            | Command::Forget
+            | Command::Language(_) => Role::Member,
+            Command::Activate(_) | Command::Settings => Role::Admin,
+            Command::Allow(_)
+            | Command::Deny(_)
+            | Command::Chats
+            | Command::Invite(_)
+            | Command::Usage(_) => Role::Owner,
+        }
+    }
+
+    /// Whether the sender of `msg` may use the command in chats that aren't
+    /// allowed yet: anyone `/activate`, and the owners their commands, e.g.
+    /// to `/allow` the chat they're in.
+    pub fn is_available_to_unauthorized(
+        &self,
+        auth: &Auth,
+        msg: &Message,
+    ) -> bool {
+        match self.required_role() {
+            Role::Owner => {
+                msg.from().is_some_and(|user| auth.is_owner(user.id))
+            }
+            Role::Admin | Role::Member => matches!(self, Command::Activate(_)),
+        }
+    }
+}
+
+/// How long invite codes are valid for, unless given with `/invite`.
+const DEFAULT_INVITE_TTL: &str = "7d";
+/// The longest an invite code can be valid for.
+const MAX_INVITE_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const INVITE_CODE_LEN: usize = 10;
+
+/// Parses a duration like `30m`, `12h` or `7d`.
+fn parse_duration(text: &str) -> Option<Duration> {
+    let text = text.trim();
+    let unit = match text.chars().last()? {
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    let amount = text[..text.len() - 1].parse::<u64>().ok()?;
+    amount
+        .checked_mul(unit)
+        .map(Duration::from_secs)
+        .filter(|d| !d.is_zero())
+}
+
+fn generate_invite_code() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(INVITE_CODE_LEN)
+        .map(|c| char::from(c).to_ascii_uppercase())
+        .collect()
+}
+
+/// Comma separated codes of the supported target languages.
+fn language_codes() -> String {
+    Language::ALL
+        .iter()
+        .map(|lang| lang.code())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The commands available with the given role, with their descriptions in
+/// the given UI language, as shown in the Telegram command menu.
+pub fn localized_bot_commands(
+    catalog: &Catalog,
+    lang: Language,
+    role: Role,
+) -> Vec<BotCommand> {
+    let languages = language_codes();
+    Command::bot_commands()
+        .into_iter()
+        .filter(|cmd| {
+            Command::parse(&cmd.command, "")
+                .map_or(true, |parsed| parsed.required_role() <= role)
+        })
+        .map(|cmd| {
+            let command = cmd.command.trim_start_matches('/').to_string();
+            let description = catalog.tr_args(
+                lang,
+                &format!("cmd-{command}"),
+                &[("languages", &languages)],
+            );
+            BotCommand::new(command, description)
+        })
+        .collect()
+}
+
+/// Renders the descriptions of the commands available with the given role
+/// in the given UI language, in the same layout as `Command::descriptions()`.
+pub fn localized_descriptions(
+    catalog: &Catalog,
+    lang: Language,
+    role: Role,
+) -> String {
+    let commands = localized_bot_commands(catalog, lang, role)
+        .into_iter()
+        .map(|cmd| format!("/{} — {}", cmd.command, cmd.description))
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!("{}\n\n{}", catalog.tr(lang, "help-header"), commands)
 }
 
+/// The reply to a `/translate` command.
+enum CommandReply {
+    /// The translation into the target, with the entry for the chat's
+    /// history.
+    Translated(Target, HistoryEntry, String),
+    /// Why the rate limits or the chat's quotas refused the translation.
+    Refused(String),
+    /// How to use the command, when the target or the text is missing.
+    Hint(String),
+}
+
+/// Translates the text following a `/translate` command, or the text of the
+/// message it replies to. The language may be left out if the chat has a
+/// default target.
+#[allow(clippy::too_many_arguments)]
+async fn translate_command(
+    google_cloud_client: &GoogleCloudClient,
+    auth: &Auth,
+    limits: &Limits,
+    catalog: &Catalog,
+    msg: &Message,
+    ui_lang: Language,
+    settings: &Settings,
+    cmd_text: &str,
+    earlier_msg_text: Option<String>,
+) -> crate::Result<CommandReply> {
+    let default_target = settings.default_target;
+    let (target, text) = match (parse_command_text(cmd_text), default_target) {
+        ((Some(target), text), _) => (target, text),
+        ((None, _), Some(lang)) => (
+            Target {
+                lang,
+                romanize: false,
+            },
+            Some(cmd_text.trim().to_string()).filter(|text| !text.is_empty()),
+        ),
+        ((None, _), None) => {
+            return Ok(CommandReply::Hint(catalog.tr_args(
+                ui_lang,
+                "invalid-target",
+                &[("languages", &language_codes())],
+            )));
+        }
+    };
+
+    let query_text = earlier_msg_text.or(text);
+    log::info!("target: {:?}, query_text: {:?}", target.code(), query_text);
+
+    let query_text = match query_text {
+        Some(text) if !text.is_empty() => text,
+        _ => {
+            return Ok(CommandReply::Hint(catalog.tr(ui_lang, "no-text")));
+        }
+    };
+
+    let translated = translate_to(
+        google_cloud_client,
+        auth,
+        limits,
+        msg,
+        settings,
+        &query_text,
+        &target,
+        Purpose::Command,
+    )
+    .await?;
+    Ok(match translated {
+        Ok(translated) => {
+            CommandReply::Translated(target, translated.entry, translated.reply)
+        }
+        Err(exceeded) => {
+            CommandReply::Refused(exceeded.message(catalog, ui_lang))
+        }
+    })
+}
+
+/// A translation, formatted for the chat.
+struct Translated {
+    /// The detected language of the original text.
+    source: Option<Language>,
+    reply: String,
+    /// What to keep in the chat's history once the reply is sent.
+    entry: HistoryEntry,
+}
+
+/// Keeps a translation sent to the chat in its history, if it has one.
+fn remember(
+    storage: &Storage,
+    settings: &Settings,
+    entry: &HistoryEntry,
+) -> crate::Result<()> {
+    if settings.history_days == 0 {
+        return Ok(());
+    }
+    let retention =
+        Duration::from_secs(u64::from(settings.history_days) * 24 * 60 * 60);
+    storage.history().insert(entry, retention)
+}
+
+/// Translates the text for the sender of `msg`, unless that would exceed
+/// their rate limits or the chat's quotas, and formats it as set for the
+/// chat.
+#[allow(clippy::too_many_arguments)]
+async fn translate_to(
+    google_cloud_client: &GoogleCloudClient,
+    auth: &Auth,
+    limits: &Limits,
+    msg: &Message,
+    settings: &Settings,
+    query_text: &str,
+    target: &Target,
+    purpose: Purpose,
+) -> crate::Result<Result<Translated, Exceeded>> {
+    let user_id = msg.from().map(|user| user.id);
+    let chars = query_text.chars().count();
+    let provider = GoogleCloudClient::PROVIDER;
+    let quotas = auth.char_quotas(&msg.chat.id);
+    if let Some(exceeded) = limits.reserve(
+        purpose,
+        provider,
+        msg.chat.id,
+        user_id,
+        chars,
+        quotas,
+    )? {
+        log::info!(
+            "Translation for [{:?}] in chat [{}] refused: {:?}",
+            user_id,
+            msg.chat.id,
+            exceeded
+        );
+        return Ok(Err(exceeded));
+    }
+
+    let tanslation = match google_cloud_client
+        .translate(query_text, &target.lang.code(), None)
+        .await
+    {
+        Ok(translation) => translation,
+        Err(err) => {
+            limits.release(provider, msg.chat.id, user_id, chars)?;
+            return Err(err);
+        }
+    };
+
+    let detected_source_language = Language::parse_code(
+        &tanslation.detected_source_language.unwrap_or_default(),
+    );
+    log::info!(
+        "detected_source_language: {:?}, translation: {:?}",
+        detected_source_language.as_ref().map(|lang| lang.name()),
+        tanslation.translated_text
+    );
+
+    let mut reply = match settings.output_format {
+        OutputFormat::Flags => format!(
+            "{}➡️{}\n{}",
+            detected_source_language
+                .as_ref()
+                .map_or("".to_string(), |lang| lang.emoji()),
+            target.lang.emoji(),
+            tanslation.translated_text
+        ),
+        OutputFormat::Plain => tanslation.translated_text.clone(),
+    };
+    if settings.show_original {
+        reply.push_str(&format!("\n📝 {}", query_text));
+    }
+    let romanize = target.romanize || settings.romanize;
+    if romanize && romanize::has_romanizable_script(query_text) {
+        reply.push_str(&format!("\n🔤 {}", romanize::romanize(query_text)));
+    }
+    let entry = HistoryEntry {
+        chat_id: msg.chat.id.0,
+        user_id: user_id.map_or(0, |id| id.0),
+        source_text: query_text.to_string(),
+        source_language: detected_source_language
+            .as_ref()
+            .map(|lang| lang.code()),
+        target: target.code(),
+        result: tanslation.translated_text,
+        created_at: Utc::now().timestamp(),
+    };
+    Ok(Ok(Translated {
+        source: detected_source_language,
+        reply,
+        entry,
+    }))
+}
+
+/// Length history entries are shortened to.
+const HISTORY_TEXT_LEN: usize = 120;
+
+fn shorten(text: &str) -> String {
+    match text.char_indices().nth(HISTORY_TEXT_LEN) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+/// The most recent translations kept in the chat matching `query`.
+fn history_text(
+    auth: &Auth,
+    catalog: &Catalog,
+    storage: &Storage,
+    ui_lang: Language,
+    msg: &Message,
+    query: Option<&str>,
+) -> crate::Result<String> {
+    let entries = storage.history().search(msg.chat.id, query)?;
+    if entries.is_empty() {
+        let settings = Settings::load(auth, storage, msg.chat.id)?;
+        let key = match settings.history_days {
+            0 => "history-off",
+            _ => "history-empty",
+        };
+        return Ok(catalog.tr(ui_lang, key));
+    }
+
+    let entries = entries.iter().map(|entry| {
+        let date = Utc
+            .timestamp_opt(entry.created_at, 0)
+            .single()
+            .map(|date| date.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        format!(
+            "{} {}➡️{}\n{}\n{}",
+            date,
+            entry.source_language.as_deref().unwrap_or("?"),
+            entry.target,
+            shorten(&entry.source_text),
+            shorten(&entry.result)
+        )
+    });
+    Ok(std::iter::once(catalog.tr(ui_lang, "history-header"))
+        .chain(entries)
+        .collect::<Vec<_>>()
+        .join("\n\n"))
+}
+
+/// The chat's remaining character quotas and the rate limits.
+fn quota_status(
+    auth: &Auth,
+    limits: &Limits,
+    catalog: &Catalog,
+    storage: &Storage,
+    ui_lang: Language,
+    chat_id: ChatId,
+) -> crate::Result<String> {
+    let (daily, monthly) = auth.char_quotas(&chat_id);
+    let usage = storage.usage();
+    let mut lines = vec![];
+    for (key, limit, used) in [
+        ("quota-daily", daily, usage.chat_chars_today(chat_id)?),
+        (
+            "quota-monthly",
+            monthly,
+            usage.chat_chars_this_month(chat_id)?,
+        ),
+    ] {
+        if let Some(limit) = limit {
+            lines.push(catalog.tr_args(
+                ui_lang,
+                key,
+                &[
+                    ("used", &used.to_string()),
+                    ("limit", &limit.to_string()),
+                    ("left", &limit.saturating_sub(used).to_string()),
+                ],
+            ));
+        }
+    }
+    if lines.is_empty() {
+        lines.push(catalog.tr(ui_lang, "quota-unlimited"));
+    }
+
+    let per_minute = |limit: u32| match limit {
+        0 => "∞".to_string(),
+        limit => limit.to_string(),
+    };
+    lines.push(catalog.tr_args(
+        ui_lang,
+        "quota-rate",
+        &[
+            ("user", &per_minute(limits.user_rate_limit())),
+            ("chat", &per_minute(limits.chat_rate_limit())),
+        ],
+    ));
+    Ok(lines.join("\n"))
+}
+
+/// The allowed chats, one per line with their id, name and where they're
+/// allowed.
+fn chats_text(auth: &Auth, catalog: &Catalog, lang: Language) -> String {
+    let chats = auth.list_chats();
+    if chats.is_empty() {
+        return catalog.tr(lang, "chats-empty");
+    }
+    let lines = chats
+        .iter()
+        .map(|(chat, allowed_by)| {
+            let source = match allowed_by {
+                AllowedBy::Config => "chats-from-config",
+                AllowedBy::Runtime => "chats-from-runtime",
+            };
+            format!(
+                "{} — {} ({})",
+                chat.id,
+                chat.name,
+                catalog.tr(lang, source)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!("{}\n{}", catalog.tr(lang, "chats-header"), lines)
+}
+
+/// Deletes an answered command message to keep the chat tidy, if enabled in
+/// the config and the bot is allowed to delete messages in the chat.
+async fn delete_command_message(
+    bot: &Bot,
+    config: &Config,
+    me: &Me,
+    msg: &Message,
+) -> crate::Result<()> {
+    if !config.delete_command_messages || msg.chat.is_private() {
+        return Ok(());
+    }
+
+    let member = bot.get_chat_member(msg.chat.id, me.id).await?;
+    if !member.can_delete_messages() {
+        log::debug!("not allowed to delete messages in chat [{}]", msg.chat.id);
+        return Ok(());
+    }
+
+    match bot.delete_message(msg.chat.id, msg.id).await {
+        Ok(_)
+        | Err(RequestError::Api(
+            ApiError::MessageToDeleteNotFound | ApiError::MessageCantBeDeleted,
+        )) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// The handler gets the whole state, as dptree injects at most 9 arguments.
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_command(
     bot: Bot,
-    auth: Arc<Auth>,
-    google_cloud_client: Arc<GoogleCloudClient>,
+    me: Me,
+    state: Arc<State>,
+    limits: Arc<Limits>,
+    catalog: Arc<Catalog>,
+    storage: Arc<Storage>,
     msg: Message,
     cmd: Command,
 ) -> crate::Result<()> {
+    let State {
+        config,
+        auth,
+        google_cloud_client,
+    } = &*state;
     log::info!(
         "new message from chat [{}] \"{}\": {:?}, cmd:",
         msg.chat.id,
@@ -115,76 +597,679 @@ pub async fn handle_command(
     log::info!("earlier_msg_text: {:?}", earlier_msg_text);
 
     let reply_to = msg.reply_to_message().unwrap_or(&msg);
+    let ui_lang = ui_language(auth, &storage, &msg)?;
 
-    let bot_send_message = |text: String| async {
-        bot.send_message(msg.chat.id, text)
-            .reply_to_message_id(reply_to.id)
-            .await
-    };
+    let user_id = msg.from().map(|user| user.id);
 
     match cmd {
         Command::Help => {
-            bot.send_message(msg.chat.id, Command::descriptions().to_string())
-                .await?
+            let role = auth.role(&bot, &msg).await?;
+            bot.send_message(
+                msg.chat.id,
+                localized_descriptions(&catalog, ui_lang, role),
+            )
+            .await?;
         }
         Command::Translate(cmd_text) | Command::T(cmd_text) => {
-            let cmd_text = cmd_text.trim();
-            let (target, text) = match parse_command_text(cmd_text) {
-                (Some(lang), text) => (lang, text),
-                (None, _) => {
-                    bot_send_message(
-                        "Invalid target language.\nValid languages: \
-                        en, de, fr, es, ru, ko"
-                            .to_string(),
-                    )
-                    .await?;
-                    return Ok(());
+            let settings = Settings::load(auth, &storage, msg.chat.id)?;
+            let (translated, text) = match translate_command(
+                google_cloud_client,
+                auth,
+                &limits,
+                &catalog,
+                &msg,
+                ui_lang,
+                &settings,
+                &cmd_text,
+                earlier_msg_text,
+            )
+            .await?
+            {
+                CommandReply::Translated(target, entry, text) => {
+                    (Some((target, entry)), text)
+                }
+                CommandReply::Refused(text) | CommandReply::Hint(text) => {
+                    (None, text)
                 }
             };
 
-            let query_text = earlier_msg_text.or(text);
-            log::info!(
-                "target: {:?}, query_text: {:?}",
-                target.name(),
-                query_text
-            );
+            let reply = bot
+                .send_message(msg.chat.id, text)
+                .reply_to_message_id(reply_to.id)
+                .await?;
+            let target = match translated {
+                Some((target, entry)) => {
+                    remember(&storage, &settings, &entry)?;
+                    Some(target)
+                }
+                None => None,
+            };
 
-            if query_text.is_none()
-                || query_text.as_ref().map_or(0, |s| s.len()) == 0
+            let messages = storage.messages();
+            let ttl = config.message_map_ttl();
+            messages.insert(
+                msg.chat.id,
+                msg.id,
+                reply.id,
+                Source::Command,
+                ttl,
+            )?;
+            if let (Some(target), Some(earlier_msg)) =
+                (target, references_earlier_msg)
             {
-                bot_send_message(
-                    "No text provided. Reply to a message \
-                    or write text after the command \ne.g. `/t en some text`"
-                        .to_string(),
-                )
-                .await?;
-                return Ok(());
+                messages.insert(
+                    msg.chat.id,
+                    earlier_msg.id,
+                    reply.id,
+                    Source::Original {
+                        target: target.code(),
+                        purpose: Purpose::Command,
+                    },
+                    ttl,
+                )?;
             }
 
-            let query_text = query_text.unwrap(); //
-            let tanslation = google_cloud_client
-                .translate(&query_text, &target.code(), None)
+            delete_command_message(&bot, config, &me, &msg).await?;
+        }
+        Command::Undo => {
+            let translation = match references_earlier_msg {
+                Some(earlier) => storage
+                    .messages()
+                    .is_reply(msg.chat.id, earlier.id)?
+                    .then_some(earlier),
+                None => None,
+            };
+            match translation {
+                Some(translation) => {
+                    bot.delete_message(msg.chat.id, translation.id).await?;
+                    storage
+                        .messages()
+                        .remove_reply(msg.chat.id, translation.id)?;
+                    delete_command_message(&bot, config, &me, &msg).await?;
+                }
+                None => {
+                    bot.send_message(
+                        msg.chat.id,
+                        catalog.tr(ui_lang, "undo-hint"),
+                    )
+                    .reply_to_message_id(msg.id)
+                    .await?;
+                }
+            }
+        }
+        Command::Allow(args) => {
+            let args = args.trim();
+            let parsed = args
+                .split_once(char::is_whitespace)
+                .and_then(|(id, name)| Some((id.parse().ok()?, name.trim())));
+            let text = match (parsed, user_id) {
+                (Some((chat_id, name)), Some(by)) => {
+                    auth.allow(ChatId(chat_id), name, by)?;
+                    catalog.tr_args(
+                        ui_lang,
+                        "chat-allowed",
+                        &[("chat_id", &chat_id.to_string()), ("name", name)],
+                    )
+                }
+                _ => catalog.tr(ui_lang, "allow-usage"),
+            };
+            bot.send_message(msg.chat.id, text)
+                .reply_to_message_id(msg.id)
                 .await?;
-
-            let detected_source_language = Language::parse_code(
-                &tanslation.detected_source_language.unwrap_or_default(),
-            );
+        }
+        Command::Deny(args) => {
+            let text = match (args.trim().parse::<i64>(), user_id) {
+                (Ok(chat_id), Some(by)) => {
+                    auth.deny(ChatId(chat_id), by)?;
+                    catalog.tr_args(
+                        ui_lang,
+                        "chat-denied",
+                        &[("chat_id", &chat_id.to_string())],
+                    )
+                }
+                _ => catalog.tr(ui_lang, "deny-usage"),
+            };
+            bot.send_message(msg.chat.id, text)
+                .reply_to_message_id(msg.id)
+                .await?;
+        }
+        Command::Invite(args) => {
+            let args = args.trim();
+            let valid_for = match args.is_empty() {
+                true => DEFAULT_INVITE_TTL,
+                false => args,
+            };
+            let ttl =
+                parse_duration(valid_for).filter(|ttl| *ttl <= MAX_INVITE_TTL);
+            let text = match (ttl, user_id) {
+                (Some(ttl), Some(by)) => {
+                    let code = generate_invite_code();
+                    storage.invites().create(&code, by, ttl)?;
+                    log::info!("Invite code created by [{}]", by);
+                    catalog.tr_args(
+                        ui_lang,
+                        "invite-created",
+                        &[("code", &code), ("valid_for", valid_for)],
+                    )
+                }
+                _ => catalog.tr(ui_lang, "invite-usage"),
+            };
+            bot.send_message(msg.chat.id, text)
+                .reply_to_message_id(msg.id)
+                .await?;
+        }
+        Command::Activate(code) => {
+            let code = code.trim().to_ascii_uppercase();
+            let chat_name = chat_name(&msg.chat);
+            let text = match user_id {
+                _ if code.is_empty() => catalog.tr(ui_lang, "activate-usage"),
+                _ if auth.chat_is_allowed(&msg.chat.id) => {
+                    catalog.tr(ui_lang, "already-allowed")
+                }
+                Some(by)
+                    if storage.invites().redeem(
+                        &code,
+                        msg.chat.id,
+                        chat_name,
+                        by,
+                    )? =>
+                {
+                    // Keep the code usable if the chat can't be allowed.
+                    if let Err(err) = auth.allow(msg.chat.id, chat_name, by) {
+                        storage.invites().restore(&code)?;
+                        return Err(err);
+                    }
+                    log::info!(
+                        "Chat [{}] \"{}\" activated by [{}]",
+                        msg.chat.id,
+                        chat_name,
+                        by
+                    );
+                    catalog.tr(ui_lang, "activated")
+                }
+                _ => catalog.tr(ui_lang, "activate-invalid"),
+            };
+            bot.send_message(msg.chat.id, text)
+                .reply_to_message_id(msg.id)
+                .await?;
+        }
+        Command::Settings => {
+            let settings = Settings::load(auth, &storage, msg.chat.id)?;
+            let (text, keyboard) = settings_menu(&catalog, ui_lang, &settings);
+            bot.send_message(msg.chat.id, text)
+                .reply_markup(keyboard)
+                .await?;
+        }
+        Command::History(query) => {
+            let query = Some(query.trim()).filter(|query| !query.is_empty());
+            bot.send_message(
+                msg.chat.id,
+                history_text(auth, &catalog, &storage, ui_lang, &msg, query)?,
+            )
+            .reply_to_message_id(msg.id)
+            .await?;
+        }
+        Command::Forget => {
+            let forgotten = match user_id {
+                Some(user_id) => storage.history().forget_user(user_id)?,
+                None => 0,
+            };
             log::info!(
-                "detected_source_language: {:?}, translation: {:?}",
-                detected_source_language.as_ref().map(|lang| lang.name()),
-                tanslation.translated_text
+                "Forgot {} history entries of [{:?}]",
+                forgotten,
+                user_id
             );
+            bot.send_message(
+                msg.chat.id,
+                catalog.tr_args(
+                    ui_lang,
+                    "history-forgotten",
+                    &[("count", &forgotten.to_string())],
+                ),
+            )
+            .reply_to_message_id(msg.id)
+            .await?;
+        }
+        Command::Language(code) => {
+            let code = code.trim();
+            let choice = match code {
+                "auto" => Some(None),
+                code => Language::parse_code(code).map(Some),
+            };
+            let text = match (choice, msg.from()) {
+                (Some(choice), Some(user)) => {
+                    storage.user_preferences().set_ui_language(
+                        user.id,
+                        choice.map(|lang| lang.code()).as_deref(),
+                    )?;
+                    log::info!(
+                        "UI language of [{}] set to {:?}",
+                        user.id,
+                        choice
+                    );
+                    // Confirmed in the language the user now gets here.
+                    let ui_lang = ui_language(auth, &storage, &msg)?;
+                    catalog.tr(ui_lang, "language-set")
+                }
+                _ => catalog.tr_args(
+                    ui_lang,
+                    "language-usage",
+                    &[
+                        ("current", &ui_lang.code()),
+                        ("languages", &language_codes()),
+                    ],
+                ),
+            };
+            bot.send_message(msg.chat.id, text)
+                .reply_to_message_id(msg.id)
+                .await?;
+        }
+        Command::Quota => {
+            bot.send_message(
+                msg.chat.id,
+                quota_status(
+                    auth,
+                    &limits,
+                    &catalog,
+                    &storage,
+                    ui_lang,
+                    msg.chat.id,
+                )?,
+            )
+            .reply_to_message_id(msg.id)
+            .await?;
+        }
+        Command::Usage(month) => {
+            let month = match month.trim() {
+                "" => Some(this_month()),
+                month => parse_month(month),
+            };
+            let (month, owner) = match (month, msg.from()) {
+                (Some(month), Some(owner)) => (month, owner),
+                _ => {
+                    bot.send_message(
+                        msg.chat.id,
+                        catalog.tr(ui_lang, "usage-usage"),
+                    )
+                    .reply_to_message_id(msg.id)
+                    .await?;
+                    return Ok(());
+                }
+            };
+            let owner_lang = ui_language_for(
+                auth,
+                &storage,
+                ChatId::from(owner.id),
+                Some(owner),
+            )?;
+            let text =
+                UsageReport::for_month(&storage, &config.prices, &month)?
+                    .to_text(&catalog, owner_lang, auth);
+            send_to_owner(
+                &bot,
+                &catalog,
+                ui_lang,
+                &msg,
+                owner,
+                text,
+                "usage-sent-privately",
+            )
+            .await?;
+        }
+        Command::Chats => {
+            let owner = match msg.from() {
+                Some(owner) => owner,
+                None => return Ok(()),
+            };
+            let owner_lang = ui_language_for(
+                auth,
+                &storage,
+                ChatId::from(owner.id),
+                Some(owner),
+            )?;
+            send_to_owner(
+                &bot,
+                &catalog,
+                ui_lang,
+                &msg,
+                owner,
+                chats_text(auth, &catalog, owner_lang),
+                "chats-sent-privately",
+            )
+            .await?;
+        }
+    };
 
-            bot_send_message(format!(
-                "{}➡️{}\n{}",
-                detected_source_language
-                    .map_or("".to_string(), |lang| lang.emoji()),
-                target.emoji(),
-                tanslation.translated_text
-            ))
-            .await?
+    Ok(())
+}
+
+/// Sends the owner `text` that is only for their eyes, in a private chat
+/// even when asked for in a group, where the reply is the message under
+/// `sent_key`, or a hint to start a private chat if that failed.
+async fn send_to_owner(
+    bot: &Bot,
+    catalog: &Catalog,
+    ui_lang: Language,
+    msg: &Message,
+    owner: &User,
+    text: String,
+    sent_key: &str,
+) -> crate::Result<()> {
+    let sent = bot.send_message(owner.id, text).await;
+    if msg.chat.is_private() {
+        sent?;
+        return Ok(());
+    }
+    let key = match sent {
+        Ok(_) => sent_key,
+        Err(err) => {
+            log::warn!("Cannot message owner [{}] privately: {err}", owner.id);
+            "chats-dm-failed"
         }
     };
+    bot.send_message(msg.chat.id, catalog.tr(ui_lang, key))
+        .reply_to_message_id(msg.id)
+        .await?;
+    Ok(())
+}
+
+/// Answers a command the sender lacks the role for.
+pub async fn handle_forbidden_command(
+    bot: Bot,
+    auth: Arc<Auth>,
+    catalog: Arc<Catalog>,
+    storage: Arc<Storage>,
+    msg: Message,
+    cmd: Command,
+) -> crate::Result<()> {
+    log::warn!(
+        "user [{:?}] in chat [{}] lacks the role for {:?}",
+        msg.from().map(|user| user.id),
+        msg.chat.id,
+        cmd
+    );
+    let key = match cmd.required_role() {
+        Role::Owner => "owner-only",
+        Role::Admin | Role::Member => "admin-only",
+    };
+    let ui_lang = ui_language(&auth, &storage, &msg)?;
+    bot.send_message(msg.chat.id, catalog.tr(ui_lang, key))
+        .reply_to_message_id(msg.id)
+        .await?;
+    Ok(())
+}
+
+/// Translates every text message into the chat's default target, if the
+/// chat has auto mode on. Messages already in the target language are left
+/// alone, as are refused translations, to keep the chat quiet.
+pub async fn handle_auto_translate(
+    bot: Bot,
+    config: Arc<Config>,
+    auth: Arc<Auth>,
+    limits: Arc<Limits>,
+    google_cloud_client: Arc<GoogleCloudClient>,
+    storage: Arc<Storage>,
+    msg: Message,
+) -> crate::Result<()> {
+    let text = match msg.text() {
+        Some(text) if !text.trim().is_empty() => text,
+        _ => return Ok(()),
+    };
+    match msg.from() {
+        Some(user) if !user.is_bot => {}
+        _ => return Ok(()),
+    }
+    let settings = Settings::load(&auth, &storage, msg.chat.id)?;
+    let target = match settings.default_target {
+        Some(lang) if settings.auto_translate => Target {
+            lang,
+            romanize: false,
+        },
+        _ => return Ok(()),
+    };
+
+    let translated = match translate_to(
+        &google_cloud_client,
+        &auth,
+        &limits,
+        &msg,
+        &settings,
+        text,
+        &target,
+        Purpose::Auto,
+    )
+    .await?
+    {
+        Ok(translated) if translated.source != Some(target.lang) => translated,
+        _ => return Ok(()),
+    };
+    let reply = bot
+        .send_message(msg.chat.id, translated.reply)
+        .reply_to_message_id(msg.id)
+        .await?;
+    remember(&storage, &settings, &translated.entry)?;
+    storage.messages().insert(
+        msg.chat.id,
+        msg.id,
+        reply.id,
+        Source::Original {
+            target: target.code(),
+            purpose: Purpose::Auto,
+        },
+        config.message_map_ttl(),
+    )?;
+    Ok(())
+}
+
+/// Translates an edited message again if the bot has replied to it before,
+/// and edits the bot's reply to match.
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_edited_message(
+    bot: Bot,
+    me: Me,
+    auth: Arc<Auth>,
+    limits: Arc<Limits>,
+    catalog: Arc<Catalog>,
+    google_cloud_client: Arc<GoogleCloudClient>,
+    storage: Arc<Storage>,
+    msg: Message,
+) -> crate::Result<()> {
+    let tracked = storage.messages().get(msg.chat.id, msg.id)?;
+    if tracked.is_empty() {
+        return Ok(());
+    }
+    log::info!(
+        "tracked message [{}] edited in chat [{}]",
+        msg.id.0,
+        msg.chat.id
+    );
+
+    let text = msg.text().unwrap_or_default();
+    let settings = Settings::load(&auth, &storage, msg.chat.id)?;
+    for tracked in tracked {
+        let (reply_text, entry) = match tracked.source {
+            Source::Command => match Command::parse(text, me.username()) {
+                Ok(Command::Translate(cmd_text) | Command::T(cmd_text)) => {
+                    let earlier_msg_text = msg
+                        .reply_to_message()
+                        .and_then(|msg| msg.text().map(|t| t.to_string()));
+                    let ui_lang = ui_language(&auth, &storage, &msg)?;
+                    match translate_command(
+                        &google_cloud_client,
+                        &auth,
+                        &limits,
+                        &catalog,
+                        &msg,
+                        ui_lang,
+                        &settings,
+                        &cmd_text,
+                        earlier_msg_text,
+                    )
+                    .await?
+                    {
+                        CommandReply::Translated(_, entry, reply_text) => {
+                            (reply_text, Some(entry))
+                        }
+                        CommandReply::Hint(reply_text) => (reply_text, None),
+                        // The earlier translation is still valid, so it's
+                        // kept, and the refusal answers the edit instead.
+                        CommandReply::Refused(refusal) => {
+                            bot.send_message(msg.chat.id, refusal)
+                                .reply_to_message_id(msg.id)
+                                .await?;
+                            continue;
+                        }
+                    }
+                }
+                _ => continue,
+            },
+            Source::Original { target, purpose } => {
+                match Target::parse(&target) {
+                    Some(target) if !text.is_empty() => {
+                        match translate_to(
+                            &google_cloud_client,
+                            &auth,
+                            &limits,
+                            &msg,
+                            &settings,
+                            text,
+                            &target,
+                            purpose,
+                        )
+                        .await?
+                        {
+                            Ok(translated) => {
+                                (translated.reply, Some(translated.entry))
+                            }
+                            Err(_) => continue,
+                        }
+                    }
+                    _ => continue,
+                }
+            }
+        };
+
+        match bot
+            .edit_message_text(
+                msg.chat.id,
+                MessageId(tracked.reply_id),
+                reply_text,
+            )
+            .await
+        {
+            Ok(_) => {
+                if let Some(entry) = entry {
+                    remember(&storage, &settings, &entry)?;
+                }
+            }
+            Err(RequestError::Api(ApiError::MessageNotModified)) => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(cmd_text: &str) -> (Option<String>, Option<String>) {
+        let (target, text) = parse_command_text(cmd_text);
+        (target.map(|target| target.code()), text)
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("30m"), Some(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration(" 12h "), Some(Duration::from_secs(43200)));
+        assert_eq!(
+            parse_duration("7d"),
+            Some(Duration::from_secs(7 * 24 * 60 * 60))
+        );
+        assert_eq!(parse_duration("0d"), None);
+        assert_eq!(parse_duration("7"), None);
+        assert_eq!(parse_duration("d"), None);
+        assert_eq!(parse_duration("7w"), None);
+        assert_eq!(parse_duration("-1d"), None);
+    }
+
+    #[test]
+    fn rejects_overflowing_durations() {
+        assert_eq!(parse_duration("99999999999999999d"), None);
+        assert_eq!(parse_duration("99999999999999999999d"), None);
+    }
+
+    #[test]
+    fn parses_the_target_and_the_text() {
+        assert_eq!(
+            parse("en Hallo Welt!"),
+            (Some("en".to_string()), Some("Hallo Welt!".to_string()))
+        );
+        assert_eq!(
+            parse("en+rom 안녕하세요"),
+            (Some("en+rom".to_string()), Some("안녕하세요".to_string()))
+        );
+    }
+
+    #[test]
+    fn trims_the_text() {
+        assert_eq!(
+            parse("  de   Hello  "),
+            (Some("de".to_string()), Some("Hello".to_string()))
+        );
+        assert_eq!(
+            parse("de\nHello\nWorld"),
+            (Some("de".to_string()), Some("Hello\nWorld".to_string()))
+        );
+    }
+
+    #[test]
+    fn parses_a_target_without_text() {
+        assert_eq!(parse("ko"), (Some("ko".to_string()), None));
+        assert_eq!(parse(""), (None, None));
+    }
+
+    #[test]
+    fn needs_whitespace_after_the_target() {
+        assert_eq!(parse("enHello").0, None);
+        assert_eq!(parse("xx Hello").0, None);
+        assert_eq!(parse("en+xyz Hello").0, None);
+    }
+
+    #[test]
+    fn shows_the_default_quotas_of_runtime_chats() {
+        let storage = Storage::in_memory().unwrap();
+        let config = Config::new(
+            String::new().into(),
+            String::new().into(),
+            Vec::new(),
+            None,
+            None,
+            false,
+        )
+        .set_default_char_quotas(100, 0);
+        let auth = Auth::new(&config, storage.clone()).unwrap();
+        auth.allow(ChatId(-5), "new", UserId(1)).unwrap();
+        let limits = Limits::new(&config, storage.clone());
+        let quotas = auth.char_quotas(&ChatId(-5));
+        limits
+            .reserve(Purpose::Command, "test", ChatId(-5), None, 30, quotas)
+            .unwrap();
+
+        let catalog = Catalog::new();
+        let status = quota_status(
+            &auth,
+            &limits,
+            &catalog,
+            &storage,
+            Language::English,
+            ChatId(-5),
+        )
+        .unwrap();
+        assert!(
+            status.starts_with("Today: 30 of 100 characters used, 70 left.\n"),
+            "{status}"
+        );
+    }
+}
@@ -1,8 +1,35 @@
+use rand::distributions::Alphanumeric;
+use rand::Rng;
 use secrecy::{ExposeSecret, Secret};
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::env::var;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::tls::check_cert_and_key;
+use crate::GoogleCloudClient;
 
 const CONFIG_PATH_ENV: &str = "CONFIG_PATH";
+/// Appended to the name of a secret to read it from a file instead.
+const FILE_SUFFIX: &str = "_FILE";
+const DEFAULT_DATABASE_PATH: &str = "./hilfmir.db";
+const DEFAULT_BIND_PORT: u16 = 8080;
+const SECRET_TOKEN_LEN: usize = 32;
+const DEFAULT_WEBHOOK_QUEUE_CAPACITY: usize = 100;
+/// Paths the HTTP server answers in both modes.
+const RESERVED_PATHS: &[&str] = &["/", "/usage", "/healthz", "/readyz"];
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_MESSAGE_MAP_TTL_HOURS: u64 = 48;
+const DEFAULT_USER_RATE_LIMIT: u32 = 10;
+const DEFAULT_CHAT_RATE_LIMIT: u32 = 30;
+const DEFAULT_DAILY_CHAR_QUOTA: u64 = 20_000;
+const DEFAULT_MONTHLY_CHAR_QUOTA: u64 = 300_000;
+/// Google Cloud Translation's list price in USD.
+const DEFAULT_GOOGLE_PRICE_PER_MILLION_CHARS: f64 = 20.0;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct SecretString(Secret<String>);
@@ -14,6 +41,18 @@ impl SecretString {
     pub fn expose_secret(&self) -> &str {
         self.0.expose_secret()
     }
+
+    /// Whether `given` is the secret. Compares in constant time, so that
+    /// the secret can't be guessed from how long the answer takes.
+    pub fn matches(&self, given: &str) -> bool {
+        let secret = self.expose_secret().as_bytes();
+        let given = given.as_bytes();
+        let diff = secret
+            .iter()
+            .zip(given)
+            .fold(0, |diff, (a, b)| diff | (a ^ b));
+        std::hint::black_box(diff) == 0 && secret.len() == given.len()
+    }
 }
 
 impl Default for SecretString {
@@ -32,24 +71,121 @@ impl From<String> for SecretString {
 pub struct AllowedChat {
     pub id: i64,
     pub name: String,
+    /// Language code of the bot's messages in this chat, e.g. `de`.
+    #[serde(default)]
+    pub ui_language: Option<String>,
+    /// Append the romanized source text to every translation.
+    #[serde(default)]
+    pub romanize: bool,
+    /// Let the members of this chat use the bot in private chats.
+    #[serde(default)]
+    pub members_may_dm: bool,
+    /// Characters this chat may translate per UTC day.
+    #[serde(default)]
+    pub daily_char_quota: Option<u64>,
+    /// Characters this chat may translate per UTC month.
+    #[serde(default)]
+    pub monthly_char_quota: Option<u64>,
 }
 
-#[derive(Deserialize, Debug, Default)]
-pub struct EnvConfig {
-    pub teloxide_token: Option<String>,
-    pub google_cloud_api_key: Option<String>,
-    pub allowed_chats: Vec<AllowedChat>,
-    pub domain_host: String,
-    pub bind_address: [u8; 4],
-    pub port: u16,
-    pub is_webhook_mode_enabled: bool,
+/// A user allowed to use the bot in a private chat, by id or username.
+#[derive(Deserialize, Debug, Clone)]
+pub struct AllowedUser {
+    pub id: Option<u64>,
+    pub username: Option<String>,
 }
 
-#[derive(Deserialize, Debug, Default)]
-pub struct TomlConfig {
-    pub teloxide_token: Option<String>,
-    pub google_cloud_api_key: Option<String>,
-    pub allowed_chats: Vec<AllowedChat>,
+impl AllowedChat {
+    pub fn new(id: i64, name: String) -> Self {
+        Self {
+            id,
+            name,
+            ui_language: None,
+            romanize: false,
+            members_may_dm: false,
+            daily_char_quota: None,
+            monthly_char_quota: None,
+        }
+    }
+}
+
+/// What the bot does when it's used in a chat that isn't allowed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UnauthorizedPolicy {
+    /// Tell the chat that the bot is private, at most once a day.
+    pub reply: bool,
+    /// Leave groups the bot was added to without authorization.
+    pub leave_groups: bool,
+    /// Tell the owners about the chat, so they can allow it.
+    pub notify_owners: bool,
+}
+
+/// Where the HTTP server listens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    /// A Unix domain socket, e.g. for a reverse proxy on the same host.
+    Unix(PathBuf),
+}
+
+impl Listen {
+    /// Parses `unix:/path/to/socket`, an IPv4 or IPv6 address or a host
+    /// name, the last ones listening on `port` unless they include one, as
+    /// in `[::1]:8080` or `localhost:8080`.
+    pub fn parse(address: &str, port: u16) -> Result<Self, String> {
+        if let Some(path) = address.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(
+                    "the path of the Unix socket is missing".to_string()
+                );
+            }
+            if cfg!(not(unix)) {
+                return Err("Unix sockets aren't supported here".to_string());
+            }
+            return Ok(Listen::Unix(PathBuf::from(path)));
+        }
+        if let Ok(address) = address.parse::<SocketAddr>() {
+            return Ok(Listen::Tcp(address));
+        }
+        let ip = address
+            .strip_prefix('[')
+            .and_then(|ip| ip.strip_suffix(']'))
+            .unwrap_or(address);
+        if let Ok(ip) = ip.parse::<IpAddr>() {
+            return Ok(Listen::Tcp(SocketAddr::new(ip, port)));
+        }
+
+        // The resolver would take e.g. `1.2.3` for `1.2.0.3`.
+        let host = address.split(':').next().unwrap_or_default();
+        if host.chars().all(|c| c.is_ascii_digit() || c == '.') {
+            return Err(format!("{address:?} is not a valid IP address"));
+        }
+        let resolved = match address.contains(':') {
+            true => address.to_socket_addrs(),
+            false => (address, port).to_socket_addrs(),
+        };
+        resolved
+            .map_err(|err| format!("cannot resolve {address:?}: {err}"))?
+            .next()
+            .map(Listen::Tcp)
+            .ok_or_else(|| format!("{address:?} has no addresses"))
+    }
+}
+
+/// The certificate and private key of the HTTP server, both PEM files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tls {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl std::fmt::Display for Listen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Listen::Tcp(address) => write!(f, "{address}"),
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -57,17 +193,54 @@ pub struct Config {
     pub teloxide_token: SecretString,
     pub google_cloud_api_key: SecretString,
     pub allowed_chats: Vec<AllowedChat>,
-    pub domain_host: String,
-    pub bind_address: [u8; 4],
-    pub port: u16,
+    /// Users allowed to use the bot in private chats.
+    pub allowed_users: Vec<AllowedUser>,
+    /// User ids of the bot owners, who may manage the allow-list.
+    pub owners: Vec<u64>,
+    /// Host name Telegram sends the updates to, required in webhook mode.
+    pub domain_host: Option<String>,
+    /// Where the HTTP server listens. Always set in webhook mode, and in
+    /// long polling mode only if `BIND_ADDRESS` is.
+    pub listen: Option<Listen>,
+    /// Serve HTTPS instead of HTTP, without a TLS terminating proxy.
+    pub tls: Option<Tls>,
     pub is_webhook_mode_enabled: bool,
     pub webhook: Option<Webhook>,
+    /// Updates received by the webhook that may wait for the dispatcher.
+    pub webhook_queue_capacity: usize,
+    /// Remove the webhook from Telegram on shutdown, so that updates wait
+    /// for the next start or another mode instead of failing to deliver.
+    pub delete_webhook_on_shutdown: bool,
+    /// How long the updates in flight may take to finish on shutdown.
+    pub shutdown_timeout_secs: u64,
+    pub database_path: String,
+    pub message_map_ttl_hours: u64,
+    pub delete_command_messages: bool,
+    pub unauthorized_policy: UnauthorizedPolicy,
+    /// Translations per minute a user may request, 0 for no limit.
+    pub user_rate_limit: u32,
+    /// Translations per minute a chat may request, 0 for no limit.
+    pub chat_rate_limit: u32,
+    /// Characters a chat not in `ALLOWED_CHATS` may translate per UTC day,
+    /// such as a chat allowed at runtime or a private chat, 0 for no limit.
+    pub default_daily_char_quota: u64,
+    /// Like `default_daily_char_quota`, per UTC month.
+    pub default_monthly_char_quota: u64,
+    /// Price per million characters by translation provider, to estimate
+    /// the cost of the usage.
+    pub prices: HashMap<String, f64>,
+    /// Bearer token for the `/usage` HTTP endpoint, which is disabled
+    /// without one.
+    pub usage_api_token: Option<SecretString>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Webhook {
     pub path: String,
     pub url: String,
+    /// Sent by Telegram in the `X-Telegram-Bot-Api-Secret-Token` header of
+    /// every update.
+    pub secret_token: SecretString,
 }
 
 impl Config {
@@ -75,154 +248,1351 @@ impl Config {
         teloxide_token: SecretString,
         google_cloud_api_key: SecretString,
         allowed_chats: Vec<AllowedChat>,
-        domain_host: String,
-        bind_address: [u8; 4],
-        port: u16,
+        domain_host: Option<String>,
+        listen: Option<Listen>,
         is_webhook_mode_enabled: bool,
     ) -> Self {
         log::info!("Allowed Chat IDs: {:?}", allowed_chats);
-        log::info!("Bind address port: {:?}", bind_address);
-        log::info!("Service port: {}", port);
+        match &listen {
+            Some(listen) => log::info!("Listening on: {}", listen),
+            None => log::info!("Not serving HTTP without BIND_ADDRESS"),
+        }
         log::info!("Webhook is enabled: {}", is_webhook_mode_enabled);
 
-        let webhook = match is_webhook_mode_enabled {
-            true => {
+        let webhook = match (is_webhook_mode_enabled, &domain_host) {
+            (true, Some(domain_host)) => {
                 let webhook_path = format!(
                     "/{}/api/v1/message",
                     teloxide_token.expose_secret()
                 );
                 let webhook_url =
                     format!("https://{}{}", domain_host, webhook_path);
-                log::info!("Webhook url: {}", webhook_url);
+                log::info!(
+                    "Webhook url: https://{}/<token>/api/v1/message",
+                    domain_host
+                );
                 Some(Webhook {
                     path: webhook_path,
                     url: webhook_url,
+                    secret_token: random_secret_token(),
                 })
             }
-            false => None,
+            _ => None,
         };
 
         Self {
             teloxide_token,
             google_cloud_api_key,
             allowed_chats,
+            allowed_users: vec![],
+            owners: vec![],
             domain_host,
-            bind_address,
-            port,
+            listen,
+            tls: None,
             is_webhook_mode_enabled,
             webhook,
+            webhook_queue_capacity: DEFAULT_WEBHOOK_QUEUE_CAPACITY,
+            delete_webhook_on_shutdown: false,
+            shutdown_timeout_secs: DEFAULT_SHUTDOWN_TIMEOUT_SECS,
+            database_path: DEFAULT_DATABASE_PATH.to_string(),
+            message_map_ttl_hours: DEFAULT_MESSAGE_MAP_TTL_HOURS,
+            delete_command_messages: false,
+            unauthorized_policy: UnauthorizedPolicy::default(),
+            user_rate_limit: DEFAULT_USER_RATE_LIMIT,
+            chat_rate_limit: DEFAULT_CHAT_RATE_LIMIT,
+            default_daily_char_quota: DEFAULT_DAILY_CHAR_QUOTA,
+            default_monthly_char_quota: DEFAULT_MONTHLY_CHAR_QUOTA,
+            prices: default_prices(),
+            usage_api_token: None,
+        }
+    }
+
+    pub fn set_allowed_users(
+        mut self,
+        allowed_users: Vec<AllowedUser>,
+    ) -> Self {
+        log::info!("Allowed users: {:?}", allowed_users);
+        self.allowed_users = allowed_users;
+        self
+    }
+
+    pub fn set_owners(mut self, owners: Vec<u64>) -> Self {
+        log::info!("Owner user IDs: {:?}", owners);
+        self.owners = owners;
+        self
+    }
+
+    pub fn set_database_path(mut self, path: String) -> Self {
+        log::info!("Database path: {}", path);
+        self.database_path = path;
+        self
+    }
+
+    pub fn set_message_map_ttl(mut self, ttl_hours: u64) -> Self {
+        log::info!("Message map ttl: {}h", ttl_hours);
+        self.message_map_ttl_hours = ttl_hours;
+        self
+    }
+
+    pub fn message_map_ttl(&self) -> Duration {
+        Duration::from_secs(self.message_map_ttl_hours * 60 * 60)
+    }
+
+    pub fn set_delete_command_messages(mut self, enabled: bool) -> Self {
+        log::info!("Delete command messages: {}", enabled);
+        self.delete_command_messages = enabled;
+        self
+    }
+
+    pub fn set_unauthorized_policy(
+        mut self,
+        policy: UnauthorizedPolicy,
+    ) -> Self {
+        log::info!("Unauthorized chats policy: {:?}", policy);
+        self.unauthorized_policy = policy;
+        self
+    }
+
+    pub fn set_rate_limits(mut self, per_user: u32, per_chat: u32) -> Self {
+        log::info!(
+            "Rate limits per minute: {} per user, {} per chat",
+            per_user,
+            per_chat
+        );
+        self.user_rate_limit = per_user;
+        self.chat_rate_limit = per_chat;
+        self
+    }
+
+    pub fn set_default_char_quotas(mut self, daily: u64, monthly: u64) -> Self {
+        log::info!(
+            "Default character quotas: {} per day, {} per month",
+            daily,
+            monthly
+        );
+        self.default_daily_char_quota = daily;
+        self.default_monthly_char_quota = monthly;
+        self
+    }
+
+    pub fn set_prices(mut self, prices: HashMap<String, f64>) -> Self {
+        log::info!("Prices per million characters: {:?}", prices);
+        self.prices = prices;
+        self
+    }
+
+    pub fn set_tls(mut self, tls: Option<Tls>) -> Self {
+        log::info!("TLS: {:?}", tls);
+        self.tls = tls;
+        self
+    }
+
+    /// Replaces the default webhook path, which contains the bot token.
+    pub fn set_webhook_path(mut self, path: Option<String>) -> Self {
+        if let (Some(webhook), Some(domain_host), Some(path)) =
+            (&mut self.webhook, &self.domain_host, path)
+        {
+            webhook.url = format!("https://{}{}", domain_host, path);
+            log::info!("Webhook url: {}", webhook.url);
+            webhook.path = path;
         }
+        self
+    }
+
+    /// Replaces the webhook secret token generated on startup.
+    pub fn set_webhook_secret_token(
+        mut self,
+        secret_token: Option<SecretString>,
+    ) -> Self {
+        if let (Some(webhook), Some(secret_token)) =
+            (&mut self.webhook, secret_token)
+        {
+            webhook.secret_token = secret_token;
+        }
+        self
+    }
+
+    pub fn set_webhook_queue_capacity(mut self, capacity: usize) -> Self {
+        log::info!("Webhook queue capacity: {}", capacity);
+        self.webhook_queue_capacity = capacity;
+        self
+    }
+
+    pub fn set_delete_webhook_on_shutdown(mut self, enabled: bool) -> Self {
+        log::info!("Delete webhook on shutdown: {}", enabled);
+        self.delete_webhook_on_shutdown = enabled;
+        self
+    }
+
+    pub fn set_shutdown_timeout(mut self, timeout_secs: u64) -> Self {
+        log::info!("Shutdown timeout: {}s", timeout_secs);
+        self.shutdown_timeout_secs = timeout_secs;
+        self
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+
+    pub fn set_usage_api_token(mut self, token: Option<SecretString>) -> Self {
+        log::info!("Usage endpoint is enabled: {}", token.is_some());
+        self.usage_api_token = token;
+        self
     }
 }
 
-pub fn load_config() -> Config {
-    let toml_config = read_config_file();
-    let env_config = get_config_from_env();
+/// Everything wrong with the configuration, so it can be fixed in one go.
+#[derive(Debug, Default)]
+pub struct ConfigError {
+    pub problems: Vec<String>,
+}
 
-    let teloxide_token = env_config
-        .teloxide_token
-        .map(|t| {
-            log::warn!("TELOXIDE_TOKEN is set in the environment");
-            t
-        }) // replace with `.inspect(...)` once it's stabilized
-        .or(toml_config.teloxide_token)
-        .expect("TELOXIDE_TOKEN not specified")
-        .into();
-    let google_cloud_api_key = env_config
-        .google_cloud_api_key
-        .map(|t| {
-            log::warn!("GOOGLE_CLOUD_API_KEY is set in the environment");
-            t
-        })
-        .or(toml_config.google_cloud_api_key)
-        .expect("GOOGLE_CLOUD_API_KEY not specified")
-        .into();
-    let allowed_chats = match env_config.allowed_chats.is_empty() {
-        false => env_config.allowed_chats,
-        true => toml_config.allowed_chats,
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid configuration:")?;
+        for problem in &self.problems {
+            write!(f, "\n  - {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// A setting, named `NAME` in the environment, `name` in the TOML file and
+/// `--name` on the command line, with `_` written as `-`.
+struct Setting {
+    name: &'static str,
+    /// Redacted by `--print-config` and in the reload diff.
+    secret: bool,
+    /// Only takes effect after a restart, not on reload.
+    restart: bool,
+}
+
+const fn setting(name: &'static str) -> Setting {
+    Setting {
+        name,
+        secret: false,
+        restart: false,
+    }
+}
+
+const fn secret(name: &'static str) -> Setting {
+    Setting {
+        name,
+        secret: true,
+        restart: false,
+    }
+}
+
+impl Setting {
+    const fn on_restart(self) -> Self {
+        Setting {
+            restart: true,
+            ..self
+        }
+    }
+}
+
+/// Every setting, read by `build_config`.
+const SETTINGS: &[Setting] = &[
+    secret("TELOXIDE_TOKEN").on_restart(),
+    secret("GOOGLE_CLOUD_API_KEY"),
+    setting("ALLOWED_CHATS"),
+    setting("ALLOWED_USERS"),
+    setting("OWNERS"),
+    setting("WEBHOOK_MODE").on_restart(),
+    setting("DOMAIN_HOST").on_restart(),
+    setting("WEBHOOK_PATH").on_restart(),
+    secret("WEBHOOK_SECRET_TOKEN").on_restart(),
+    setting("WEBHOOK_QUEUE_CAPACITY").on_restart(),
+    setting("DELETE_WEBHOOK_ON_SHUTDOWN"),
+    setting("BIND_ADDRESS").on_restart(),
+    setting("BIND_PORT").on_restart(),
+    setting("TLS_CERT_PATH").on_restart(),
+    setting("TLS_KEY_PATH").on_restart(),
+    setting("DATABASE_PATH").on_restart(),
+    setting("SHUTDOWN_TIMEOUT_SECS"),
+    setting("MESSAGE_MAP_TTL_HOURS"),
+    setting("DELETE_COMMAND_MESSAGES"),
+    setting("UNAUTHORIZED_REPLY"),
+    setting("UNAUTHORIZED_LEAVE_GROUPS"),
+    setting("UNAUTHORIZED_NOTIFY_OWNERS"),
+    setting("USER_RATE_LIMIT"),
+    setting("CHAT_RATE_LIMIT"),
+    setting("DEFAULT_DAILY_CHAR_QUOTA"),
+    setting("DEFAULT_MONTHLY_CHAR_QUOTA"),
+    setting("PRICE_PER_MILLION_CHARS"),
+    secret("USAGE_API_TOKEN"),
+];
+
+fn find_setting(name: &str) -> Option<&'static Setting> {
+    SETTINGS
+        .iter()
+        .find(|setting| setting.name.eq_ignore_ascii_case(name))
+}
+
+/// The upper case name of a setting, or of the `NAME_FILE` a secret can be
+/// read from, if `key` is one.
+fn setting_key(key: &str) -> Option<String> {
+    let name = key.to_uppercase();
+    let known = match name.strip_suffix(FILE_SUFFIX) {
+        Some(secret) => find_setting(secret).is_some_and(|s| s.secret),
+        None => find_setting(&name).is_some(),
+    };
+    known.then_some(name)
+}
+
+/// Reads a secret from a file, such as a Docker or Kubernetes secret,
+/// warning if others than its owner may read it.
+fn read_secret_file(name: &str, path: &str) -> Result<String, String> {
+    let secret = std::fs::read_to_string(path).map_err(|err| {
+        format!("Cannot read {name}{FILE_SUFFIX} {path}: {err}")
+    })?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Ok(metadata) = std::fs::metadata(path) {
+            let mode = metadata.permissions().mode() & 0o777;
+            if mode & 0o077 != 0 {
+                log::warn!(
+                    "{path} has mode {mode:o}, readable by others than its owner"
+                );
+            }
+        }
+    }
+    Ok(secret.trim_end().to_string())
+}
+
+fn default_value(name: &str) -> Option<String> {
+    let value = match name {
+        "ALLOWED_CHATS" | "ALLOWED_USERS" | "OWNERS" => "[]".to_string(),
+        "WEBHOOK_MODE"
+        | "DELETE_WEBHOOK_ON_SHUTDOWN"
+        | "DELETE_COMMAND_MESSAGES"
+        | "UNAUTHORIZED_REPLY"
+        | "UNAUTHORIZED_LEAVE_GROUPS"
+        | "UNAUTHORIZED_NOTIFY_OWNERS" => "false".to_string(),
+        "BIND_PORT" => DEFAULT_BIND_PORT.to_string(),
+        "WEBHOOK_QUEUE_CAPACITY" => DEFAULT_WEBHOOK_QUEUE_CAPACITY.to_string(),
+        "DATABASE_PATH" => DEFAULT_DATABASE_PATH.to_string(),
+        "SHUTDOWN_TIMEOUT_SECS" => DEFAULT_SHUTDOWN_TIMEOUT_SECS.to_string(),
+        "MESSAGE_MAP_TTL_HOURS" => DEFAULT_MESSAGE_MAP_TTL_HOURS.to_string(),
+        "USER_RATE_LIMIT" => DEFAULT_USER_RATE_LIMIT.to_string(),
+        "CHAT_RATE_LIMIT" => DEFAULT_CHAT_RATE_LIMIT.to_string(),
+        "DEFAULT_DAILY_CHAR_QUOTA" => DEFAULT_DAILY_CHAR_QUOTA.to_string(),
+        "DEFAULT_MONTHLY_CHAR_QUOTA" => DEFAULT_MONTHLY_CHAR_QUOTA.to_string(),
+        "PRICE_PER_MILLION_CHARS" => {
+            serde_json::json!(default_prices()).to_string()
+        }
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// A webhook secret token, for when none is configured. Telegram is told
+/// the new one on every start.
+fn random_secret_token() -> SecretString {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SECRET_TOKEN_LEN)
+        .map(char::from)
+        .collect::<String>()
+        .into()
+}
+
+fn default_prices() -> HashMap<String, f64> {
+    HashMap::from([(
+        GoogleCloudClient::PROVIDER.to_string(),
+        DEFAULT_GOOGLE_PRICE_PER_MILLION_CHARS,
+    )])
+}
+
+/// Where the value of a setting comes from, each overriding the previous.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layer {
+    Default,
+    File,
+    Env,
+    Flag,
+}
+
+impl std::fmt::Display for Layer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Layer::Default => "default",
+            Layer::File => "config file",
+            Layer::Env => "environment",
+            Layer::Flag => "command line",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// A setting's value and where it comes from.
+struct Value {
+    value: String,
+    layer: Layer,
+    /// The file a secret was read from.
+    path: Option<String>,
+}
+
+/// The value of every setting and the layer it comes from: the defaults,
+/// overridden by the TOML file, then the environment, then the command line
+/// flags. Secrets can also be read from the file in `NAME_FILE`.
+pub struct ConfigLayers {
+    config_path: (String, Layer),
+    values: HashMap<&'static str, Value>,
+    problems: Vec<String>,
+}
+
+impl ConfigLayers {
+    /// Reads the layers, with `env` standing in for the process environment
+    /// and `flags` holding the `(name, value)` pairs given as `--name value`.
+    pub fn load(
+        env: impl Fn(&str) -> Option<String>,
+        flags: &[(String, String)],
+    ) -> Self {
+        let mut problems = vec![];
+        let mut config_path_flag = None;
+        let mut flag_values = HashMap::new();
+        for (flag, value) in flags {
+            let name = flag.replace('-', "_");
+            if name.eq_ignore_ascii_case(CONFIG_PATH_ENV) {
+                config_path_flag = Some(value.clone());
+                continue;
+            }
+            match setting_key(&name) {
+                Some(name) => {
+                    flag_values.insert(name, value.clone());
+                }
+                None => problems.push(format!("Unknown flag --{flag}")),
+            }
+        }
+
+        let config_path = match (config_path_flag, env(CONFIG_PATH_ENV)) {
+            (Some(path), _) => (path, Layer::Flag),
+            (None, Some(path)) => (path, Layer::Env),
+            (None, None) => {
+                log::warn!(
+                    "CONFIG_PATH_ENV not set, assuming default config.toml"
+                );
+                ("./config.toml".to_string(), Layer::Default)
+            }
+        };
+        let file_values =
+            read_config_file(&config_path.0).unwrap_or_else(|err| {
+                problems.push(err);
+                HashMap::new()
+            });
+
+        let mut values = HashMap::new();
+        for setting in SETTINGS {
+            let layers: [(Layer, &Lookup); 3] = [
+                (Layer::Flag, &|key| flag_values.get(key).cloned()),
+                (Layer::Env, &env),
+                (Layer::File, &|key| file_values.get(key).cloned()),
+            ];
+            let value = layers
+                .into_iter()
+                .find_map(|(layer, lookup)| {
+                    let (value, path) = lookup_in(setting, lookup)
+                        .unwrap_or_else(|err| {
+                            problems.push(err);
+                            None
+                        })?;
+                    Some(Value { value, layer, path })
+                })
+                .or_else(|| {
+                    default_value(setting.name).map(|value| Value {
+                        value,
+                        layer: Layer::Default,
+                        path: None,
+                    })
+                });
+            if let Some(value) = value {
+                values.insert(setting.name, value);
+            }
+        }
+
+        Self {
+            config_path,
+            values,
+            problems,
+        }
+    }
+
+    pub fn config_path(&self) -> &str {
+        &self.config_path.0
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(|value| value.value.as_str())
+    }
+
+    pub fn layer(&self, name: &str) -> Option<Layer> {
+        self.values.get(name).map(|value| value.layer)
+    }
+
+    /// The file the secret `name` was read from, if it was.
+    pub fn secret_file(&self, name: &str) -> Option<&str> {
+        self.values
+            .get(name)
+            .and_then(|value| value.path.as_deref())
+    }
+
+    /// Every setting with its effective value and where it comes from, one
+    /// per line, with the secrets redacted.
+    pub fn describe(&self) -> String {
+        let (path, layer) = &self.config_path;
+        let mut lines = vec![format!("{CONFIG_PATH_ENV}={path}  # {layer}")];
+        for setting in SETTINGS {
+            let name = setting.name;
+            lines.push(match self.values.get(name) {
+                Some(Value {
+                    layer,
+                    path: Some(path),
+                    ..
+                }) => format!("{name}=<redacted>  # {layer}, from {path}"),
+                Some(Value { layer, .. }) if setting.secret => {
+                    format!("{name}=<redacted>  # {layer}")
+                }
+                Some(Value { value, layer, .. }) => {
+                    format!("{name}={value}  # {layer}")
+                }
+                None => format!("# {name} is not set"),
+            });
+        }
+        lines.join("\n")
+    }
+
+    /// The settings whose values differ in `new`, one line each, with the
+    /// secrets redacted.
+    pub fn diff(&self, new: &ConfigLayers) -> Vec<String> {
+        let mut changes = vec![];
+        for setting in SETTINGS {
+            let (old, new) = (self.get(setting.name), new.get(setting.name));
+            if old == new {
+                continue;
+            }
+            let mut change = match setting.secret {
+                true => format!("{} changed", setting.name),
+                false => format!(
+                    "{}: {} -> {}",
+                    setting.name,
+                    old.unwrap_or("<unset>"),
+                    new.unwrap_or("<unset>")
+                ),
+            };
+            if setting.restart {
+                change.push_str(" (takes effect after a restart)");
+            }
+            changes.push(change);
+        }
+        changes
+    }
+}
+
+/// Finds the value of a setting in a layer by its name.
+type Lookup<'a> = dyn Fn(&str) -> Option<String> + 'a;
+
+/// The value of the setting in a layer, and the file it was read from if
+/// it's a secret given as `NAME_FILE`.
+fn lookup_in(
+    setting: &Setting,
+    lookup: &Lookup,
+) -> Result<Option<(String, Option<String>)>, String> {
+    let name = setting.name;
+    let value = lookup(name);
+    if !setting.secret {
+        return Ok(value.map(|value| (value, None)));
+    }
+    match (value, lookup(&format!("{name}{FILE_SUFFIX}"))) {
+        (Some(_), Some(_)) => Err(format!(
+            "Only one of {name} and {name}{FILE_SUFFIX} can be set"
+        )),
+        (Some(value), None) => Ok(Some((value, None))),
+        (None, Some(path)) => {
+            let secret = read_secret_file(name, &path)?;
+            Ok(Some((secret, Some(path))))
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+/// Reads the settings from the TOML file, which is optional. Lists and
+/// tables are turned into JSON, as they are given in the environment.
+fn read_config_file(
+    config_path: &str,
+) -> Result<HashMap<String, String>, String> {
+    let text = match std::fs::read_to_string(config_path) {
+        Ok(text) => text,
+        Err(_) => {
+            log::warn!("Failed to read {config_path} Toml config file");
+            return Ok(HashMap::new());
+        }
     };
+    let table = toml::from_str::<toml::value::Table>(&text)
+        .map_err(|err| format!("Failed to parse {config_path}: {err}"))?;
+
+    let mut values = HashMap::new();
+    for (key, value) in table {
+        let name = match setting_key(&key) {
+            Some(name) => name,
+            None => {
+                log::warn!("Unknown setting {key} in {config_path}");
+                continue;
+            }
+        };
+        let value = match value {
+            toml::Value::String(value) => value,
+            toml::Value::Array(_) | toml::Value::Table(_) => {
+                serde_json::to_string(&value).map_err(|err| {
+                    format!("Bad format of {key} in {config_path}: {err}")
+                })?
+            }
+            value => value.to_string(),
+        };
+        values.insert(name, value);
+    }
+    Ok(values)
+}
+
+/// Loads the config from the TOML file, the process environment and the
+/// command line `flags`.
+pub fn load_config(flags: &[(String, String)]) -> Result<Config, ConfigError> {
+    build_config(&ConfigLayers::load(|name| var(name).ok(), flags))
+}
+
+/// Checks the settings and builds the config from them, or reports all
+/// problems with them.
+pub fn build_config(layers: &ConfigLayers) -> Result<Config, ConfigError> {
+    for setting in SETTINGS.iter().filter(|setting| setting.secret) {
+        if layers.secret_file(setting.name).is_some() {
+            continue;
+        }
+        match layers.layer(setting.name) {
+            Some(Layer::Env) => {
+                log::warn!("{} is set in the environment", setting.name)
+            }
+            Some(Layer::Flag) => {
+                log::warn!("{} is set on the command line", setting.name)
+            }
+            _ => {}
+        }
+    }
+
+    let mut vars = Vars {
+        layers,
+        problems: layers.problems.clone(),
+    };
+
+    let teloxide_token = vars.required("TELOXIDE_TOKEN");
+
+    let google_cloud_api_key = vars.required("GOOGLE_CLOUD_API_KEY");
+
+    let allowed_chats: Vec<AllowedChat> =
+        vars.json("ALLOWED_CHATS").unwrap_or_default();
+
+    let allowed_users: Vec<AllowedUser> =
+        vars.json("ALLOWED_USERS").unwrap_or_default();
+    for (i, user) in allowed_users.iter().enumerate() {
+        if user.id.is_none() && user.username.is_none() {
+            vars.problems.push(format!(
+                "ALLOWED_USERS entry {} needs an id or a username",
+                i + 1
+            ));
+        }
+    }
+
+    let owners = vars.json("OWNERS").unwrap_or_default();
+
+    let is_webhook_mode_enabled = vars.bool("WEBHOOK_MODE");
+
+    let domain_host = vars
+        .string("DOMAIN_HOST")
+        .filter(|domain_host| !domain_host.is_empty());
+    if is_webhook_mode_enabled && domain_host.is_none() {
+        vars.problems
+            .push("DOMAIN_HOST is required in webhook mode".to_string());
+    }
+
+    let port = vars.parse("BIND_PORT", "a port number").unwrap_or_default();
+
+    // Webhook mode needs the server, long polling mode only serves the
+    // probes and the usage report if asked to.
+    let listen = match vars
+        .string("BIND_ADDRESS")
+        .filter(|address| !address.is_empty())
+    {
+        Some(address) => Listen::parse(&address, port)
+            .map_err(|err| {
+                vars.problems.push(format!("Invalid BIND_ADDRESS: {err}"))
+            })
+            .ok(),
+        None if is_webhook_mode_enabled => {
+            Some(Listen::Tcp(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))))
+        }
+        None => None,
+    };
+
+    let database_path = vars.string("DATABASE_PATH").unwrap_or_default();
+
+    let shutdown_timeout_secs = vars
+        .parse("SHUTDOWN_TIMEOUT_SECS", "an integer")
+        .unwrap_or_default();
+
+    let message_map_ttl_hours = vars
+        .parse("MESSAGE_MAP_TTL_HOURS", "an integer")
+        .unwrap_or_default();
+
+    let delete_command_messages = vars.bool("DELETE_COMMAND_MESSAGES");
+
+    let unauthorized_policy = UnauthorizedPolicy {
+        reply: vars.bool("UNAUTHORIZED_REPLY"),
+        leave_groups: vars.bool("UNAUTHORIZED_LEAVE_GROUPS"),
+        notify_owners: vars.bool("UNAUTHORIZED_NOTIFY_OWNERS"),
+    };
+
+    let user_rate_limit = vars
+        .parse("USER_RATE_LIMIT", "an integer")
+        .unwrap_or_default();
+
+    let chat_rate_limit = vars
+        .parse("CHAT_RATE_LIMIT", "an integer")
+        .unwrap_or_default();
+
+    let default_daily_char_quota = vars
+        .parse("DEFAULT_DAILY_CHAR_QUOTA", "an integer")
+        .unwrap_or_default();
+
+    let default_monthly_char_quota = vars
+        .parse("DEFAULT_MONTHLY_CHAR_QUOTA", "an integer")
+        .unwrap_or_default();
+
+    let prices = vars.json("PRICE_PER_MILLION_CHARS").unwrap_or_default();
+
+    let usage_api_token = vars
+        .string("USAGE_API_TOKEN")
+        .filter(|token| !token.is_empty());
+
+    let tls = match (vars.string("TLS_CERT_PATH"), vars.string("TLS_KEY_PATH"))
+    {
+        (Some(cert_path), Some(key_path)) => Some(Tls {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+        }),
+        (None, None) => None,
+        _ => {
+            vars.problems.push(
+                "TLS_CERT_PATH and TLS_KEY_PATH have to be set together"
+                    .to_string(),
+            );
+            None
+        }
+    };
+    if let Some(tls) = &tls {
+        if let Err(err) = check_cert_and_key(&tls.cert_path, &tls.key_path) {
+            vars.problems.push(err);
+        }
+        if let Some(Listen::Unix(_)) = listen {
+            vars.problems
+                .push("TLS can't be used with a Unix socket".to_string());
+        }
+    }
+
+    let webhook_path =
+        vars.string("WEBHOOK_PATH").filter(|path| !path.is_empty());
+    if let Some(path) = &webhook_path {
+        if !path.starts_with('/') || path.contains(char::is_whitespace) {
+            vars.problems.push(format!(
+                "WEBHOOK_PATH has to start with / and have no spaces, got {path:?}"
+            ));
+        }
+        if RESERVED_PATHS.contains(&path.as_str()) {
+            vars.problems.push(format!(
+                "WEBHOOK_PATH can't be {path}, which the server uses for itself"
+            ));
+        }
+    }
+
+    let webhook_queue_capacity = match vars
+        .parse::<usize>("WEBHOOK_QUEUE_CAPACITY", "a positive integer")
+    {
+        Some(0) => {
+            vars.problems.push(
+                "WEBHOOK_QUEUE_CAPACITY has to be a positive integer, got 0"
+                    .to_string(),
+            );
+            0
+        }
+        capacity => capacity.unwrap_or_default(),
+    };
+
+    let delete_webhook_on_shutdown = vars.bool("DELETE_WEBHOOK_ON_SHUTDOWN");
+
+    let webhook_secret_token = vars
+        .string("WEBHOOK_SECRET_TOKEN")
+        .filter(|token| !token.is_empty());
+    if let Some(token) = &webhook_secret_token {
+        let valid = token.len() <= 256
+            && token
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid {
+            vars.problems.push(
+                "WEBHOOK_SECRET_TOKEN has to be up to 256 letters, digits, _ or -"
+                    .to_string(),
+            );
+        }
+    }
+
+    let (teloxide_token, google_cloud_api_key) =
+        match (teloxide_token, google_cloud_api_key) {
+            (Some(token), Some(key)) if vars.problems.is_empty() => {
+                (token, key)
+            }
+            _ => {
+                return Err(ConfigError {
+                    problems: vars.problems,
+                })
+            }
+        };
 
     if allowed_chats.is_empty() {
         log::warn!("No Chats are allowed to communicate with the bot");
     }
 
-    Config::new(
-        teloxide_token,
-        google_cloud_api_key,
+    Ok(Config::new(
+        teloxide_token.into(),
+        google_cloud_api_key.into(),
         allowed_chats,
-        env_config.domain_host,
-        env_config.bind_address,
-        env_config.port,
-        env_config.is_webhook_mode_enabled,
+        domain_host,
+        listen,
+        is_webhook_mode_enabled,
+    )
+    .set_allowed_users(allowed_users)
+    .set_owners(owners)
+    .set_database_path(database_path)
+    .set_message_map_ttl(message_map_ttl_hours)
+    .set_delete_command_messages(delete_command_messages)
+    .set_unauthorized_policy(unauthorized_policy)
+    .set_rate_limits(user_rate_limit, chat_rate_limit)
+    .set_default_char_quotas(
+        default_daily_char_quota,
+        default_monthly_char_quota,
     )
+    .set_prices(prices)
+    .set_usage_api_token(usage_api_token.map(SecretString::new))
+    .set_tls(tls)
+    .set_webhook_path(webhook_path)
+    .set_webhook_queue_capacity(webhook_queue_capacity)
+    .set_delete_webhook_on_shutdown(delete_webhook_on_shutdown)
+    .set_shutdown_timeout(shutdown_timeout_secs)
+    .set_webhook_secret_token(webhook_secret_token.map(SecretString::new)))
 }
 
-pub fn get_config_from_env() -> EnvConfig {
-    let allowed_chats = serde_json::from_str::<Vec<AllowedChat>>(
-        &var("ALLOWED_CHATS")
-            .ok()
-            .unwrap_or_else(|| "[]".to_string()),
-    )
-    .expect("Bad format of ALLOWED_CHATS");
+/// Reads the settings, noting the problems with their values instead of
+/// giving up on the first one.
+struct Vars<'a> {
+    layers: &'a ConfigLayers,
+    problems: Vec<String>,
+}
 
-    let google_cloud_api_key = var("GOOGLE_CLOUD_API_KEY").ok();
+impl Vars<'_> {
+    fn string(&self, name: &str) -> Option<String> {
+        self.layers.get(name).map(str::to_string)
+    }
 
-    let teloxide_token = var("TELOXIDE_TOKEN").ok();
+    fn required(&mut self, name: &str) -> Option<String> {
+        let val = self.string(name);
+        if val.is_none() {
+            self.problems.push(format!("{name} not specified"));
+        }
+        val
+    }
 
-    let domain_host =
-        var("DOMAIN_HOST").expect("DOMAIN_HOST env variable missing");
+    /// The parsed value of the setting, if it's set and valid.
+    fn parse<T: FromStr>(&mut self, name: &str, expected: &str) -> Option<T> {
+        let val = self.string(name)?;
+        match val.parse() {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                self.problems
+                    .push(format!("{name} has to be {expected}, got {val:?}"));
+                None
+            }
+        }
+    }
+
+    fn bool(&mut self, name: &str) -> bool {
+        let val = match self.string(name) {
+            Some(val) => val,
+            None => return false,
+        };
+        val.to_lowercase().parse().unwrap_or_else(|_| {
+            self.problems.push(format!(
+                "Cannot convert {name} to bool. Applicable values are only \"true\" or \"false\", got {val:?}"
+            ));
+            false
+        })
+    }
 
-    let bind_address: [u8; 4] = var("BIND_ADDRESS")
-        .unwrap_or_else(|_| "0.0.0.0".to_string())
-        .split('.')
-        .filter_map(|s| s.parse::<u8>().ok())
-        .collect::<Vec<_>>()
-        .try_into()
-        .expect("BIND_HOST value has to be an of the form X.X.X.X (eg 0.0.0.0 or 127.0.0.1)");
+    fn json<T: DeserializeOwned>(&mut self, name: &str) -> Option<T> {
+        let val = self.string(name)?;
+        match serde_json::from_str(&val) {
+            Ok(parsed) => Some(parsed),
+            Err(err) => {
+                self.problems.push(format!("Bad format of {name}: {err}"));
+                None
+            }
+        }
+    }
+}
 
-    let port = var("BIND_PORT")
-        .unwrap_or_else(|_| "8080".to_string())
-        .parse::<u16>()
-        .expect("BIND_PORT value has to be an integer");
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let is_webhook_mode_enabled: bool = var("WEBHOOK_MODE")
-        .map(|val| val.to_lowercase())
-        .unwrap_or_else(|_| "false".to_string())
-        .parse()
-        .expect(
-            "Cannot convert WEBHOOK_MODE to bool. Applicable values are only \"true\" or \"false\"",
+    /// Builds the config from `env` alone, without a config file.
+    fn build(env: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let mut env: HashMap<String, String> = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        env.entry(CONFIG_PATH_ENV.to_string())
+            .or_insert_with(|| "/nonexistent/config.toml".to_string());
+        build_config(&ConfigLayers::load(|name| env.get(name).cloned(), &[]))
+    }
+
+    /// Reads the layers from `env` and `flags`, like the process would.
+    fn layers(env: &[(&str, &str)], flags: &[(&str, &str)]) -> ConfigLayers {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let flags: Vec<(String, String)> = flags
+            .iter()
+            .map(|(flag, value)| (flag.to_string(), value.to_string()))
+            .collect();
+        ConfigLayers::load(|name| env.get(name).cloned(), &flags)
+    }
+
+    /// A file in the temporary directory, deleted when dropped.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("hilfmir-{}-{name}", std::process::id()));
+            std::fs::write(&path, contents).unwrap();
+            Self(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    const REQUIRED: [(&str, &str); 2] = [
+        ("TELOXIDE_TOKEN", "123:abc"),
+        ("GOOGLE_CLOUD_API_KEY", "key"),
+    ];
+
+    fn with_required<'a>(
+        env: &[(&'a str, &'a str)],
+    ) -> Vec<(&'a str, &'a str)> {
+        REQUIRED.iter().chain(env).copied().collect()
+    }
+
+    #[test]
+    fn matches_secrets() {
+        let secret = SecretString::new("s3cret".to_string());
+        assert!(secret.matches("s3cret"));
+        assert!(!secret.matches("s3creT"));
+        assert!(!secret.matches("s3cre"));
+        assert!(!secret.matches("s3crets"));
+        assert!(!secret.matches(""));
+        assert!(SecretString::default().matches(""));
+    }
+
+    #[test]
+    fn builds_with_the_required_settings() {
+        let config = build(&REQUIRED).unwrap();
+        assert!(!config.is_webhook_mode_enabled);
+        assert!(config.allowed_chats.is_empty());
+    }
+
+    #[test]
+    fn serves_http_in_long_polling_mode_only_with_a_bind_address() {
+        assert_eq!(build(&REQUIRED).unwrap().listen, None);
+        let config =
+            build(&with_required(&[("BIND_ADDRESS", "127.0.0.1")])).unwrap();
+        assert_eq!(
+            config.listen,
+            Some(Listen::Tcp(([127, 0, 0, 1], 8080).into()))
+        );
+        let config = build(&with_required(&[
+            ("WEBHOOK_MODE", "true"),
+            ("DOMAIN_HOST", "bot.example.com"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            config.listen,
+            Some(Listen::Tcp(([0, 0, 0, 0], 8080).into()))
         );
+    }
 
-    EnvConfig {
-        teloxide_token,
-        google_cloud_api_key,
-        allowed_chats,
-        domain_host,
-        bind_address,
-        port,
-        is_webhook_mode_enabled,
+    #[test]
+    fn reports_every_problem_together() {
+        let err = build(&[("WEBHOOK_MODE", "yes"), ("BIND_PORT", "http")])
+            .unwrap_err();
+        assert_eq!(
+            err.problems,
+            [
+                "TELOXIDE_TOKEN not specified",
+                "GOOGLE_CLOUD_API_KEY not specified",
+                "Cannot convert WEBHOOK_MODE to bool. Applicable values are \
+                 only \"true\" or \"false\", got \"yes\"",
+                "BIND_PORT has to be a port number, got \"http\"",
+            ]
+        );
     }
-}
 
-pub fn read_config_file() -> TomlConfig {
-    let config_path = var(CONFIG_PATH_ENV).unwrap_or_else(|_| {
-        log::warn!("CONFIG_PATH_ENV not set, assuming default config.toml");
-        "./config.toml".to_string()
-    });
+    #[test]
+    fn domain_host_is_required_in_webhook_mode_only() {
+        assert!(build(&with_required(&[("WEBHOOK_MODE", "false")])).is_ok());
+        let err =
+            build(&with_required(&[("WEBHOOK_MODE", "true")])).unwrap_err();
+        assert_eq!(err.problems, ["DOMAIN_HOST is required in webhook mode"]);
+        let config = build(&with_required(&[
+            ("WEBHOOK_MODE", "true"),
+            ("DOMAIN_HOST", "bot.example.com"),
+        ]))
+        .unwrap();
+        assert!(config.is_webhook_mode_enabled);
+    }
 
-    std::fs::read(&config_path)
-        .map_err(|e| {
-            log::warn!("Failed to read {config_path} Toml config file");
-            e.to_string()
-        })
-        .ok()
-        .map(|bytes| {
-            toml::from_slice::<TomlConfig>(&bytes)
-                .map_err(|e| e.to_string())
-                .unwrap_or_else(|err| {
-                    log::error!("failed to prase Toml config file: {err}");
-                    std::process::exit(1);
-                })
-        })
-        .unwrap_or_default()
+    #[test]
+    fn rejects_malformed_allowed_chats() {
+        let err = build(&with_required(&[(
+            "ALLOWED_CHATS",
+            r#"[{"id": -1, "name": "team"}"#,
+        )]))
+        .unwrap_err();
+        assert_eq!(err.problems.len(), 1);
+        assert!(
+            err.problems[0].starts_with("Bad format of ALLOWED_CHATS: "),
+            "{:?}",
+            err.problems
+        );
+    }
+
+    #[test]
+    fn rejects_bad_bind_ports() {
+        for port in ["-1", "65536", "8080x", ""] {
+            let err =
+                build(&with_required(&[("BIND_PORT", port)])).unwrap_err();
+            assert_eq!(
+                err.problems,
+                [format!("BIND_PORT has to be a port number, got {port:?}")]
+            );
+        }
+    }
+
+    #[test]
+    fn parses_listen_addresses() {
+        let tcp = |address: &str| Ok(Listen::Tcp(address.parse().unwrap()));
+        let cases = [
+            ("127.0.0.1", tcp("127.0.0.1:8080")),
+            ("0.0.0.0:9000", tcp("0.0.0.0:9000")),
+            ("::1", tcp("[::1]:8080")),
+            ("[::1]", tcp("[::1]:8080")),
+            ("[::]:9000", tcp("[::]:9000")),
+            (
+                "unix:/run/hilfmir.sock",
+                Ok(Listen::Unix(PathBuf::from("/run/hilfmir.sock"))),
+            ),
+            (
+                "unix:",
+                Err("the path of the Unix socket is missing".to_string()),
+            ),
+            (
+                "1.2.3",
+                Err("\"1.2.3\" is not a valid IP address".to_string()),
+            ),
+            (
+                "256.0.0.1:80",
+                Err("\"256.0.0.1:80\" is not a valid IP address".to_string()),
+            ),
+        ];
+        for (address, expected) in cases {
+            assert_eq!(Listen::parse(address, 8080), expected, "{address}");
+        }
+        // What the resolver says about these varies.
+        for address in ["[::1", "localhost:http", "localhost:8080:1"] {
+            assert!(Listen::parse(address, 8080).is_err(), "{address}");
+        }
+
+        for (address, port) in [("localhost", 8080), ("localhost:9000", 9000)] {
+            match Listen::parse(address, 8080) {
+                Ok(Listen::Tcp(resolved)) => {
+                    assert!(resolved.ip().is_loopback(), "{address}");
+                    assert_eq!(resolved.port(), port, "{address}");
+                }
+                other => panic!("{address} parsed as {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn parses_the_tls_files() {
+        let testdata = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls");
+        let cert = format!("{testdata}/self-signed.pem");
+        let key = format!("{testdata}/self-signed.key");
+        let config = build(&with_required(&[
+            ("TLS_CERT_PATH", &cert),
+            ("TLS_KEY_PATH", &key),
+        ]))
+        .unwrap();
+        assert!(config.tls.is_some());
+
+        // The certificate given as the key.
+        let err = build(&with_required(&[
+            ("TLS_CERT_PATH", &cert),
+            ("TLS_KEY_PATH", &cert),
+        ]))
+        .unwrap_err();
+        assert_eq!(
+            err.problems,
+            [format!("No private key found at the start of {cert}")]
+        );
+    }
+
+    #[test]
+    fn allowed_users_need_an_id_or_a_username() {
+        let err = build(&with_required(&[(
+            "ALLOWED_USERS",
+            r#"[{"id": 1}, {"username": "someone"}, {}]"#,
+        )]))
+        .unwrap_err();
+        assert_eq!(
+            err.problems,
+            ["ALLOWED_USERS entry 3 needs an id or a username"]
+        );
+    }
+
+    #[test]
+    fn flags_override_the_env_which_overrides_the_file() {
+        let file = TempFile::new(
+            "layers.toml",
+            "user_rate_limit = 1\nchat_rate_limit = 1\n\
+            message_map_ttl_hours = 1\n",
+        );
+        let layers = layers(
+            &[
+                (CONFIG_PATH_ENV, file.path()),
+                ("CHAT_RATE_LIMIT", "2"),
+                ("MESSAGE_MAP_TTL_HOURS", "2"),
+            ],
+            &[("message-map-ttl-hours", "3")],
+        );
+        let setting = |name| (layers.get(name), layers.layer(name));
+        assert_eq!(setting("USER_RATE_LIMIT"), (Some("1"), Some(Layer::File)));
+        assert_eq!(setting("CHAT_RATE_LIMIT"), (Some("2"), Some(Layer::Env)));
+        assert_eq!(
+            setting("MESSAGE_MAP_TTL_HOURS"),
+            (Some("3"), Some(Layer::Flag))
+        );
+        assert_eq!(
+            setting("SHUTDOWN_TIMEOUT_SECS"),
+            (Some("10"), Some(Layer::Default))
+        );
+        assert_eq!(setting("USAGE_API_TOKEN"), (None, None));
+        assert_eq!(layers.config_path(), file.path());
+        assert!(layers.problems.is_empty());
+    }
+
+    #[test]
+    fn never_shows_the_secrets() {
+        let secret = TempFile::new("never-shown", "file-secret\n");
+        let old = layers(
+            &[
+                (CONFIG_PATH_ENV, "/nonexistent/config.toml"),
+                ("TELOXIDE_TOKEN", "123:old-secret"),
+                ("GOOGLE_CLOUD_API_KEY_FILE", secret.path()),
+                ("USER_RATE_LIMIT", "5"),
+            ],
+            &[("usage-api-token", "flag-secret")],
+        );
+        let new = layers(
+            &[
+                (CONFIG_PATH_ENV, "/nonexistent/config.toml"),
+                ("TELOXIDE_TOKEN", "123:new-secret"),
+                ("GOOGLE_CLOUD_API_KEY", "env-secret"),
+                ("USER_RATE_LIMIT", "6"),
+            ],
+            &[],
+        );
+        let description = old.describe();
+        assert!(description.contains("TELOXIDE_TOKEN=<redacted>"));
+        assert!(description.contains("USER_RATE_LIMIT=5  # environment"));
+        let changes = old.diff(&new);
+        assert_eq!(
+            changes,
+            [
+                "TELOXIDE_TOKEN changed (takes effect after a restart)",
+                "GOOGLE_CLOUD_API_KEY changed",
+                "USER_RATE_LIMIT: 5 -> 6",
+                "USAGE_API_TOKEN changed",
+            ]
+        );
+        for shown in [description, new.describe(), changes.join("\n")] {
+            for secret in [
+                "old-secret",
+                "new-secret",
+                "env-secret",
+                "file-secret",
+                "flag-secret",
+            ] {
+                assert!(!shown.contains(secret), "{secret} in {shown}");
+            }
+        }
+    }
+
+    #[test]
+    fn the_readme_lists_the_settings_needing_a_restart() {
+        let readme = include_str!("../README.md");
+        let list = readme
+            .lines()
+            .find_map(|line| {
+                line.split_once(" only take effect after a restart.")
+            })
+            .expect("No list of the settings needing a restart")
+            .0;
+        let listed: Vec<&str> = list.split('`').skip(1).step_by(2).collect();
+        let restart: Vec<&str> = SETTINGS
+            .iter()
+            .filter(|setting| setting.restart)
+            .map(|setting| setting.name)
+            .collect();
+        assert_eq!(listed, restart);
+    }
+
+    #[test]
+    fn reads_secrets_from_files() {
+        let token = TempFile::new("token", "123:from-file \n");
+        let key = TempFile::new("key", "key-from-file\n");
+        let file = TempFile::new(
+            "secrets.toml",
+            &format!("google_cloud_api_key_file = {:?}\n", key.path()),
+        );
+        let layers = layers(
+            &[
+                (CONFIG_PATH_ENV, file.path()),
+                ("TELOXIDE_TOKEN_FILE", token.path()),
+            ],
+            &[],
+        );
+        assert_eq!(layers.secret_file("TELOXIDE_TOKEN"), Some(token.path()));
+        assert_eq!(
+            layers.secret_file("GOOGLE_CLOUD_API_KEY"),
+            Some(key.path())
+        );
+        assert_eq!(layers.layer("GOOGLE_CLOUD_API_KEY"), Some(Layer::File));
+
+        let config = build_config(&layers).unwrap();
+        assert_eq!(config.teloxide_token.expose_secret(), "123:from-file");
+        assert_eq!(
+            config.google_cloud_api_key.expose_secret(),
+            "key-from-file"
+        );
+    }
+
+    #[test]
+    fn takes_a_secret_or_its_file() {
+        let token = TempFile::new("either", "123:from-file");
+        let err =
+            build(&with_required(&[("TELOXIDE_TOKEN_FILE", token.path())]))
+                .unwrap_err();
+        assert_eq!(
+            err.problems,
+            [
+                "Only one of TELOXIDE_TOKEN and TELOXIDE_TOKEN_FILE can be set",
+                "TELOXIDE_TOKEN not specified",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_unreadable_secret_files() {
+        let missing = std::env::temp_dir().join("hilfmir-missing-secret");
+        let missing = missing.to_str().unwrap();
+        let directory = std::env::temp_dir();
+        let directory = directory.to_str().unwrap();
+        let err = build(&[
+            ("TELOXIDE_TOKEN_FILE", missing),
+            ("GOOGLE_CLOUD_API_KEY_FILE", directory),
+        ])
+        .unwrap_err();
+        assert_eq!(err.problems.len(), 4, "{:?}", err.problems);
+        assert!(err.problems[0].starts_with(&format!(
+            "Cannot read TELOXIDE_TOKEN_FILE {missing}: "
+        )));
+        assert!(err.problems[1].starts_with(&format!(
+            "Cannot read GOOGLE_CLOUD_API_KEY_FILE {directory}: "
+        )));
+        // Without their files, the secrets are missing.
+        assert_eq!(
+            err.problems[2..],
+            [
+                "TELOXIDE_TOKEN not specified",
+                "GOOGLE_CLOUD_API_KEY not specified"
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_webhook_paths_and_tokens() {
+        let too_long = "a".repeat(257);
+        let cases = [
+            (
+                ("WEBHOOK_PATH", "telegram"),
+                "WEBHOOK_PATH has to start with / and have no spaces, \
+                got \"telegram\"",
+            ),
+            (
+                ("WEBHOOK_SECRET_TOKEN", "s3cret!"),
+                "WEBHOOK_SECRET_TOKEN has to be up to 256 letters, digits, \
+                _ or -",
+            ),
+            (
+                ("WEBHOOK_SECRET_TOKEN", too_long.as_str()),
+                "WEBHOOK_SECRET_TOKEN has to be up to 256 letters, digits, \
+                _ or -",
+            ),
+        ];
+        for (setting, problem) in cases {
+            let err = build(&with_required(&[setting])).unwrap_err();
+            assert_eq!(err.problems, [problem], "{}", setting.0);
+        }
+
+        let longest = "a".repeat(256);
+        let config = build(&with_required(&[
+            ("WEBHOOK_MODE", "true"),
+            ("DOMAIN_HOST", "bot.example.com"),
+            ("WEBHOOK_PATH", "/telegram"),
+            ("WEBHOOK_SECRET_TOKEN", &longest),
+        ]))
+        .unwrap();
+        let webhook = config.webhook.unwrap();
+        assert_eq!(webhook.url, "https://bot.example.com/telegram");
+        assert!(webhook.secret_token.matches(&longest));
+    }
 }
@@ -26,3 +26,41 @@ impl From<teloxide::RequestError> for AppError {
         }
     }
 }
+
+impl From<rusqlite::Error> for AppError {
+    fn from(e: rusqlite::Error) -> Self {
+        AppError {
+            msg: format!("rusqlite::Error: {}", e),
+        }
+    }
+}
+
+impl From<hyper::Error> for AppError {
+    fn from(e: hyper::Error) -> Self {
+        AppError {
+            msg: format!("hyper::Error: {}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for AppError {
+    fn from(e: std::io::Error) -> Self {
+        AppError {
+            msg: format!("std::io::Error: {}", e),
+        }
+    }
+}
+
+impl From<tokio::task::JoinError> for AppError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        AppError {
+            msg: format!("tokio::task::JoinError: {}", e),
+        }
+    }
+}
+
+impl From<crate::ConfigError> for AppError {
+    fn from(e: crate::ConfigError) -> Self {
+        AppError { msg: e.to_string() }
+    }
+}
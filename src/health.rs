@@ -0,0 +1,222 @@
+use axum::extract::Extension;
+use axum::http::StatusCode;
+use axum::Json;
+use reqwest::Url;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use teloxide::prelude::*;
+
+use crate::storage::Storage;
+use crate::Reloader;
+
+/// How long the outcome of a check calling another service is kept, so
+/// that frequent probes don't call it every time.
+const CHECK_TTL: Duration = Duration::from_secs(30);
+/// Below the 1s probe timeout Kubernetes has by default.
+const CHECK_TIMEOUT: Duration = Duration::from_millis(800);
+
+/// The outcome of a check calling another service, with when it was made.
+#[derive(Default)]
+struct CachedCheck {
+    last: tokio::sync::Mutex<Option<(Instant, Result<String, String>)>>,
+}
+
+impl CachedCheck {
+    /// The outcome of the last check if it's recent, or else of `check`.
+    /// Probes arriving meanwhile wait for it rather than check again.
+    async fn run(
+        &self,
+        name: &str,
+        check: impl Future<Output = crate::Result<String>>,
+    ) -> Result<String, String> {
+        self.run_at(name, check, Instant::now()).await
+    }
+
+    async fn run_at(
+        &self,
+        name: &str,
+        check: impl Future<Output = crate::Result<String>>,
+        now: Instant,
+    ) -> Result<String, String> {
+        let mut last = self.last.lock().await;
+        if let Some((checked_at, outcome)) = &*last {
+            if now.saturating_duration_since(*checked_at) < CHECK_TTL {
+                return outcome.clone();
+            }
+        }
+        let outcome = match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+            Ok(outcome) => outcome.map_err(|err| err.msg),
+            Err(_) => Err(format!("No answer in {:?}", CHECK_TIMEOUT)),
+        };
+        if let Err(err) = &outcome {
+            log::warn!("The {name} check failed: {err}");
+        }
+        *last = Some((now, outcome.clone()));
+        outcome
+    }
+}
+
+/// What the probes know about the bot that they can't ask anyone else.
+pub struct Health {
+    bot: Bot,
+    /// Where Telegram should send the updates, in webhook mode.
+    webhook_url: Option<Url>,
+    webhook_registered: AtomicBool,
+    webhook_check: CachedCheck,
+    translator_check: CachedCheck,
+}
+
+impl Health {
+    pub fn new(bot: Bot, webhook_url: Option<Url>) -> Self {
+        Self {
+            bot,
+            webhook_url,
+            webhook_registered: AtomicBool::new(false),
+            webhook_check: CachedCheck::default(),
+            translator_check: CachedCheck::default(),
+        }
+    }
+
+    pub fn set_webhook_registered(&self, registered: bool) {
+        self.webhook_registered.store(registered, Ordering::Relaxed);
+    }
+
+    /// Whether Telegram still has the webhook, which another instance of
+    /// the bot may have replaced or deleted since it was registered.
+    async fn check_webhook(&self, url: &Url) -> crate::Result<String> {
+        let info = self.bot.get_webhook_info().await?;
+        if info.url.as_ref() != Some(url) {
+            return Err(crate::AppError {
+                msg: "Telegram has another webhook".to_string(),
+            });
+        }
+        if let Some(err) = info.last_error_message {
+            log::warn!("Telegram failed to deliver an update: {err}");
+        }
+        Ok("Registered with Telegram".to_string())
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Check {
+    ok: bool,
+    detail: String,
+}
+
+impl Check {
+    /// The detail of a failure is kept vague, as the probes answer anyone.
+    /// What went wrong is logged instead.
+    fn new(outcome: Result<String, String>, failure: &str) -> Self {
+        match outcome {
+            Ok(detail) => Check { ok: true, detail },
+            Err(_) => Check {
+                ok: false,
+                detail: failure.to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    ready: bool,
+    checks: BTreeMap<&'static str, Check>,
+}
+
+/// Liveness: answers as long as the process does.
+pub async fn healthz() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Readiness: whether the webhook is registered, the translator reachable
+/// and the database writable. Answers 503 unless they all are.
+pub async fn readyz(
+    Extension(health): Extension<Arc<Health>>,
+    Extension(reloader): Extension<Arc<Reloader>>,
+    Extension(storage): Extension<Storage>,
+) -> (StatusCode, Json<Readiness>) {
+    let mut checks: BTreeMap<_, Check> = BTreeMap::new();
+    if let Some(url) = &health.webhook_url {
+        let outcome = match health.webhook_registered.load(Ordering::Relaxed) {
+            true => {
+                health
+                    .webhook_check
+                    .run("webhook", health.check_webhook(url))
+                    .await
+            }
+            false => Err("Not registered yet".to_string()),
+        };
+        checks.insert(
+            "webhook",
+            Check::new(outcome, "Not registered with Telegram"),
+        );
+    }
+
+    let google_cloud_client = reloader.state().google_cloud_client.clone();
+    let outcome = health
+        .translator_check
+        .run("translator", google_cloud_client.check())
+        .await;
+    checks.insert("translator", Check::new(outcome, "Unreachable"));
+
+    let outcome = storage
+        .check_writable()
+        .map(|()| "Writable".to_string())
+        .map_err(|err| {
+            log::warn!("The storage check failed: {err}");
+            err.msg
+        });
+    checks.insert("storage", Check::new(outcome, "Not writable"));
+
+    let ready = checks.values().all(|check| check.ok);
+    if !ready {
+        log::warn!("Not ready: {:?}", checks);
+    }
+    let status = match ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(Readiness { ready, checks }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[tokio::test]
+    async fn keeps_the_outcome_of_a_check() {
+        let check = CachedCheck::default();
+        let calls = AtomicUsize::new(0);
+        let failing = || async {
+            calls.fetch_add(1, Ordering::Relaxed);
+            Err(crate::AppError {
+                msg: "down".to_string(),
+            })
+        };
+        let now = Instant::now();
+        let down = Err("down".to_string());
+        assert_eq!(check.run_at("test", failing(), now).await, down);
+        assert_eq!(check.run_at("test", failing(), now).await, down);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        // Checked again once the outcome is old.
+        let up = async { Ok("up".to_string()) };
+        let later = now + CHECK_TTL;
+        assert_eq!(check.run_at("test", up, later).await, Ok("up".into()));
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_slow_checks() {
+        let check = CachedCheck::default();
+        let slow = async {
+            tokio::time::sleep(CHECK_TIMEOUT * 2).await;
+            Ok("up".to_string())
+        };
+        assert!(check.run("test", slow).await.is_err());
+    }
+}
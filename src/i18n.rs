@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use teloxide::types::{ChatId, Message, User};
+
+use crate::language::Language;
+use crate::storage::Storage;
+use crate::Auth;
+
+/// Message bundles shipped with the bot, one per UI language.
+const BUNDLES: [(Language, &str); 6] = [
+    (Language::English, include_str!("../locales/en.toml")),
+    (Language::German, include_str!("../locales/de.toml")),
+    (Language::French, include_str!("../locales/fr.toml")),
+    (Language::Spanish, include_str!("../locales/es.toml")),
+    (Language::Russian, include_str!("../locales/ru.toml")),
+    (Language::Korean, include_str!("../locales/ko.toml")),
+];
+
+const FALLBACK: Language = Language::English;
+
+/// Catalog of the bot's own UI messages.
+///
+/// Messages are looked up by key and may contain `{name}` placeholders.
+/// Keys missing from a bundle fall back to English.
+pub struct Catalog {
+    bundles: HashMap<Language, HashMap<String, String>>,
+}
+
+impl Catalog {
+    pub fn new() -> Self {
+        let bundles = BUNDLES
+            .iter()
+            .map(|(lang, source)| {
+                let bundle = toml::from_str::<HashMap<String, String>>(source)
+                    .unwrap_or_else(|err| {
+                        panic!("Bad message bundle for {}: {err}", lang.code())
+                    });
+                (*lang, bundle)
+            })
+            .collect();
+        Self { bundles }
+    }
+
+    pub fn tr(&self, lang: Language, key: &str) -> String {
+        self.tr_args(lang, key, &[])
+    }
+
+    pub fn tr_args(
+        &self,
+        lang: Language,
+        key: &str,
+        args: &[(&str, &str)],
+    ) -> String {
+        let message = [lang, FALLBACK]
+            .iter()
+            .find_map(|lang| self.bundles.get(lang)?.get(key))
+            .cloned()
+            .unwrap_or_else(|| {
+                log::warn!("Missing UI message \"{key}\"");
+                key.to_string()
+            });
+
+        args.iter().fold(message, |message, (name, value)| {
+            message.replace(&format!("{{{name}}}"), value)
+        })
+    }
+}
+
+impl Default for Catalog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Picks the language to talk to the user in. In order of precedence:
+/// the UI language of the chat set at runtime or in the config, the user's
+/// preference, and the language of the user's Telegram client.
+pub fn ui_language(
+    auth: &Auth,
+    storage: &Storage,
+    msg: &Message,
+) -> crate::Result<Language> {
+    ui_language_for(auth, storage, msg.chat.id, msg.from())
+}
+
+/// Like [`ui_language`], for the user in the chat.
+pub fn ui_language_for(
+    auth: &Auth,
+    storage: &Storage,
+    chat_id: ChatId,
+    user: Option<&User>,
+) -> crate::Result<Language> {
+    let chat_language = storage
+        .chat_settings()
+        .get(chat_id)?
+        .ui_language
+        .as_deref()
+        .and_then(Language::parse_code)
+        .or_else(|| auth.get_ui_language(&chat_id));
+    if let Some(lang) = chat_language {
+        return Ok(lang);
+    }
+
+    let user_language = match user {
+        Some(user) => storage
+            .user_preferences()
+            .get(user.id)?
+            .ui_language
+            .as_deref()
+            .and_then(Language::parse_code)
+            .or_else(|| {
+                user.language_code
+                    .as_deref()
+                    .and_then(Language::parse_language_tag)
+            }),
+        None => None,
+    };
+    Ok(user_language.unwrap_or(FALLBACK))
+}
@@ -0,0 +1,76 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Language {
+    English,
+    German,
+    French,
+    Spanish,
+    Russian,
+    Korean,
+}
+
+impl Language {
+    pub const ALL: [Language; 6] = [
+        Language::English,
+        Language::German,
+        Language::French,
+        Language::Spanish,
+        Language::Russian,
+        Language::Korean,
+    ];
+
+    pub fn code(&self) -> String {
+        match self {
+            Language::English => "en",
+            Language::German => "de",
+            Language::French => "fr",
+            Language::Spanish => "es",
+            Language::Russian => "ru",
+            Language::Korean => "ko",
+        }
+        .to_owned()
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Language::English => "english",
+            Language::German => "german",
+            Language::French => "french",
+            Language::Spanish => "spanish",
+            Language::Russian => "russian",
+            Language::Korean => "korean",
+        }
+        .to_owned()
+    }
+
+    pub fn emoji(&self) -> String {
+        match self {
+            Language::English => "🇬🇧",
+            Language::German => "🇩🇪",
+            Language::French => "🇫🇷",
+            Language::Spanish => "🇪🇸",
+            Language::Russian => "🇷🇺",
+            Language::Korean => "🇰🇷",
+        }
+        .to_owned()
+    }
+
+    pub fn parse_code(code: &str) -> Option<Self> {
+        let lang = match code {
+            "en" => Some(Language::English),
+            "de" => Some(Language::German),
+            "fr" => Some(Language::French),
+            "es" => Some(Language::Spanish),
+            "ru" => Some(Language::Russian),
+            "ko" => Some(Language::Korean),
+            _ => None,
+        };
+        log::debug!("{} => {:?}", code, lang);
+        lang
+    }
+
+    /// Parses an IETF language tag as sent by Telegram clients, e.g. `de-AT`.
+    pub fn parse_language_tag(tag: &str) -> Option<Self> {
+        let code = tag.split(['-', '_']).next().unwrap_or_default();
+        Self::parse_code(&code.to_lowercase())
+    }
+}
@@ -2,14 +2,44 @@ mod auth;
 mod commands;
 mod config;
 mod error;
+mod health;
+mod i18n;
+mod language;
+mod limits;
+mod menu;
+mod reload;
+mod romanize;
+mod server;
+mod settings;
+mod shutdown;
+mod storage;
+mod tls;
 mod translate;
+mod unauthorized;
+mod usage;
 mod webhook;
 
-pub use auth::Auth;
-pub use commands::{handle_command, Command};
-pub use config::{load_config, Config};
+pub use auth::{Auth, Role};
+pub use commands::{
+    handle_auto_translate, handle_command, handle_edited_message,
+    handle_forbidden_command, Command,
+};
+pub use config::{
+    build_config, load_config, Config, ConfigError, ConfigLayers,
+};
 pub use error::AppError;
+pub use health::Health;
+pub use i18n::Catalog;
+pub use language::Language;
+pub use limits::Limits;
+pub use menu::publish_commands;
+pub use reload::{watch_config, Reloader, State};
+pub use server::spawn_server;
+pub use settings::handle_settings_callback;
+pub use shutdown::shutdown_signal;
+pub use storage::{ChatSettings, Storage, UserPreferences};
 pub use translate::GoogleCloudClient;
+pub use unauthorized::{handle_my_chat_member, handle_unauthorized_message};
 pub use webhook::webhook;
 
 type Result<T> = std::result::Result<T, AppError>;
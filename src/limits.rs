@@ -0,0 +1,437 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use teloxide::types::{ChatId, UserId};
+
+use crate::i18n::Catalog;
+use crate::language::Language;
+use crate::storage::{QuotaPeriod, Storage};
+use crate::Config;
+
+/// Buckets are dropped once there are this many, keeping the ones in use.
+const MAX_BUCKETS: usize = 10_000;
+
+/// What a translation is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Purpose {
+    /// Asked for with `/translate`, or an edit of such a message.
+    Command,
+    /// A message translated by auto mode. Every message in the chat is sent
+    /// to the translator, so auto mode has rate limits of its own and may
+    /// use only part of the chat's quotas, leaving the rest to commands.
+    Auto,
+}
+
+impl Purpose {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Purpose::Command => "command",
+            Purpose::Auto => "auto",
+        }
+    }
+
+    pub fn parse(code: &str) -> Option<Self> {
+        match code {
+            "command" => Some(Purpose::Command),
+            "auto" => Some(Purpose::Auto),
+            _ => None,
+        }
+    }
+}
+
+/// Auto mode may use up to 1/AUTO_QUOTA_DIVISOR of the chat's quotas.
+const AUTO_QUOTA_DIVISOR: u64 = 2;
+
+/// Why a translation was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exceeded {
+    /// Too many translations in a short time; retry after the duration.
+    RateLimit(Duration),
+    DailyQuota,
+    MonthlyQuota,
+}
+
+impl Exceeded {
+    /// A friendly explanation for the chat.
+    pub fn message(&self, catalog: &Catalog, lang: Language) -> String {
+        match self {
+            Exceeded::RateLimit(retry_after) => catalog.tr_args(
+                lang,
+                "rate-limited",
+                &[("seconds", &retry_after.as_secs().max(1).to_string())],
+            ),
+            Exceeded::DailyQuota => catalog.tr(lang, "daily-quota-exceeded"),
+            Exceeded::MonthlyQuota => {
+                catalog.tr(lang, "monthly-quota-exceeded")
+            }
+        }
+    }
+}
+
+/// A token bucket per key, holding up to `per_minute` tokens and refilling
+/// at `per_minute` tokens a minute.
+struct RateLimiter<K> {
+    per_minute: AtomicU32,
+    buckets: Mutex<HashMap<K, (f64, Instant)>>,
+}
+
+impl<K: Eq + Hash + Copy> RateLimiter<K> {
+    fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute: AtomicU32::new(per_minute),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn per_minute(&self) -> u32 {
+        self.per_minute.load(Ordering::Relaxed)
+    }
+
+    /// Changes the limit, keeping the tokens left in the buckets.
+    fn set_per_minute(&self, per_minute: u32) {
+        self.per_minute.store(per_minute, Ordering::Relaxed);
+    }
+
+    fn capacity(&self) -> f64 {
+        self.per_minute() as f64
+    }
+
+    fn refilled(&self, tokens: f64, updated_at: Instant, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(updated_at);
+        let refill = elapsed.as_secs_f64() * self.capacity() / 60.0;
+        (tokens + refill).min(self.capacity())
+    }
+
+    /// Takes a token for the key, or returns how long until one is
+    /// available. A limit of 0 disables rate limiting.
+    fn try_acquire(&self, key: K) -> Result<(), Duration> {
+        self.try_acquire_at(key, Instant::now())
+    }
+
+    fn try_acquire_at(&self, key: K, now: Instant) -> Result<(), Duration> {
+        if self.per_minute() == 0 {
+            return Ok(());
+        }
+        let mut buckets = self.buckets.lock().expect("rate limiter poisoned");
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, (tokens, updated_at)| {
+                self.refilled(*tokens, *updated_at, now) < self.capacity()
+            });
+        }
+        let (tokens, updated_at) =
+            buckets.entry(key).or_insert((self.capacity(), now));
+        let available = self.refilled(*tokens, *updated_at, now);
+        if available < 1.0 {
+            let missing = 1.0 - available;
+            return Err(Duration::from_secs_f64(
+                missing * 60.0 / self.capacity(),
+            ));
+        }
+        *tokens = available - 1.0;
+        *updated_at = now;
+        Ok(())
+    }
+
+    /// Gives back a token taken by `try_acquire`.
+    fn refund(&self, key: K) {
+        if let Some((tokens, _)) = self
+            .buckets
+            .lock()
+            .expect("rate limiter poisoned")
+            .get_mut(&key)
+        {
+            *tokens = (*tokens + 1.0).min(self.capacity());
+        }
+    }
+}
+
+/// Rate limits per user and per chat, and the character quotas of the
+/// allowed chats. Checked before every call to the translator.
+///
+/// Shared by every handler and kept across config reloads, which only
+/// change the limits, so the buckets aren't refilled by a reload.
+pub struct Limits {
+    storage: Storage,
+    users: RateLimiter<UserId>,
+    chats: RateLimiter<ChatId>,
+    auto_users: RateLimiter<UserId>,
+    auto_chats: RateLimiter<ChatId>,
+}
+
+impl Limits {
+    pub fn new(config: &Config, storage: Storage) -> Self {
+        Self {
+            storage,
+            users: RateLimiter::new(config.user_rate_limit),
+            chats: RateLimiter::new(config.chat_rate_limit),
+            auto_users: RateLimiter::new(config.user_rate_limit),
+            auto_chats: RateLimiter::new(config.chat_rate_limit),
+        }
+    }
+
+    /// Applies the rate limits of a reloaded config.
+    pub fn reconfigure(&self, config: &Config) {
+        self.users.set_per_minute(config.user_rate_limit);
+        self.chats.set_per_minute(config.chat_rate_limit);
+        self.auto_users.set_per_minute(config.user_rate_limit);
+        self.auto_chats.set_per_minute(config.chat_rate_limit);
+    }
+
+    fn limiters(
+        &self,
+        purpose: Purpose,
+    ) -> (&RateLimiter<UserId>, &RateLimiter<ChatId>) {
+        match purpose {
+            Purpose::Command => (&self.users, &self.chats),
+            Purpose::Auto => (&self.auto_users, &self.auto_chats),
+        }
+    }
+
+    /// Takes a token from the rate limits of the user and the chat for the
+    /// `purpose` and counts `chars` characters for `provider` towards the
+    /// chat's `quotas`, unless one of them would be exceeded. The
+    /// characters are given back with `release` if the translation fails.
+    pub fn reserve(
+        &self,
+        purpose: Purpose,
+        provider: &str,
+        chat_id: ChatId,
+        user_id: Option<UserId>,
+        chars: usize,
+        quotas: (Option<u64>, Option<u64>),
+    ) -> crate::Result<Option<Exceeded>> {
+        let (users, chats) = self.limiters(purpose);
+        if let Some(user_id) = user_id {
+            if let Err(retry_after) = users.try_acquire(user_id) {
+                return Ok(Some(Exceeded::RateLimit(retry_after)));
+            }
+        }
+        if let Err(retry_after) = chats.try_acquire(chat_id) {
+            if let Some(user_id) = user_id {
+                users.refund(user_id);
+            }
+            return Ok(Some(Exceeded::RateLimit(retry_after)));
+        }
+
+        let quotas = match purpose {
+            Purpose::Command => quotas,
+            Purpose::Auto => {
+                let share = |quota: Option<u64>| {
+                    quota.map(|quota| quota / AUTO_QUOTA_DIVISOR)
+                };
+                (share(quotas.0), share(quotas.1))
+            }
+        };
+
+        let quota = self.storage.usage().reserve(
+            provider,
+            chat_id,
+            user_id,
+            chars as u64,
+            quotas,
+        );
+        if !matches!(quota, Ok(None)) {
+            // Refused translations don't count towards the rate limits.
+            if let Some(user_id) = user_id {
+                users.refund(user_id);
+            }
+            chats.refund(chat_id);
+        }
+        match quota? {
+            None => Ok(None),
+            Some(QuotaPeriod::Day) => Ok(Some(Exceeded::DailyQuota)),
+            Some(QuotaPeriod::Month) => Ok(Some(Exceeded::MonthlyQuota)),
+        }
+    }
+
+    /// Gives back the characters of a reservation that wasn't translated.
+    pub fn release(
+        &self,
+        provider: &str,
+        chat_id: ChatId,
+        user_id: Option<UserId>,
+        chars: usize,
+    ) -> crate::Result<()> {
+        self.storage
+            .usage()
+            .release(provider, chat_id, user_id, chars as u64)
+    }
+
+    pub fn user_rate_limit(&self) -> u32 {
+        self.users.per_minute()
+    }
+
+    pub fn chat_rate_limit(&self) -> u32 {
+        self.chats.per_minute()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_tokens_until_the_bucket_is_empty() {
+        let limiter = RateLimiter::new(2);
+        let now = Instant::now();
+        assert_eq!(limiter.try_acquire_at(1, now), Ok(()));
+        assert_eq!(limiter.try_acquire_at(1, now), Ok(()));
+        assert_eq!(
+            limiter.try_acquire_at(1, now),
+            Err(Duration::from_secs(30))
+        );
+        // Every key has its own bucket.
+        assert_eq!(limiter.try_acquire_at(2, now), Ok(()));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = RateLimiter::new(60);
+        let now = Instant::now();
+        for _ in 0..60 {
+            limiter.try_acquire_at(1, now).unwrap();
+        }
+        assert!(limiter.try_acquire_at(1, now).is_err());
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.try_acquire_at(1, later), Ok(()));
+        assert!(limiter.try_acquire_at(1, later).is_err());
+
+        // Refilling stops at the capacity.
+        let much_later = later + Duration::from_secs(10 * 60);
+        for _ in 0..60 {
+            limiter.try_acquire_at(1, much_later).unwrap();
+        }
+        assert!(limiter.try_acquire_at(1, much_later).is_err());
+    }
+
+    #[test]
+    fn refunds_tokens() {
+        let limiter = RateLimiter::new(1);
+        let now = Instant::now();
+        limiter.try_acquire_at(1, now).unwrap();
+        limiter.refund(1);
+        assert_eq!(limiter.try_acquire_at(1, now), Ok(()));
+    }
+
+    #[test]
+    fn zero_disables_the_limit() {
+        let limiter = RateLimiter::new(0);
+        let now = Instant::now();
+        for _ in 0..1000 {
+            assert_eq!(limiter.try_acquire_at(1, now), Ok(()));
+        }
+    }
+
+    #[test]
+    fn keeps_the_tokens_when_the_limit_changes() {
+        let limiter = RateLimiter::new(1);
+        let now = Instant::now();
+        limiter.try_acquire_at(1, now).unwrap();
+        limiter.set_per_minute(2);
+        assert_eq!(
+            limiter.try_acquire_at(1, now),
+            Err(Duration::from_secs(30))
+        );
+    }
+
+    fn limits() -> Limits {
+        let config = Config::new(
+            String::new().into(),
+            String::new().into(),
+            Vec::new(),
+            None,
+            None,
+            false,
+        );
+        Limits::new(&config, Storage::in_memory().unwrap())
+    }
+
+    #[test]
+    fn auto_mode_has_its_own_rate_limits() {
+        let limits = limits();
+        limits.users.set_per_minute(1);
+        limits.auto_users.set_per_minute(1);
+        let (chat, user) = (ChatId(-1), Some(UserId(1)));
+        let reserve = |purpose| {
+            limits
+                .reserve(purpose, "test", chat, user, 1, (None, None))
+                .unwrap()
+        };
+        assert_eq!(reserve(Purpose::Auto), None);
+        assert!(matches!(
+            reserve(Purpose::Auto),
+            Some(Exceeded::RateLimit(_))
+        ));
+        assert_eq!(reserve(Purpose::Command), None);
+    }
+
+    #[test]
+    fn auto_mode_uses_part_of_the_quota() {
+        let limits = limits();
+        let (chat, user) = (ChatId(-1), Some(UserId(1)));
+        let quotas = (Some(100), None);
+        let reserve = |purpose, chars| {
+            limits
+                .reserve(purpose, "test", chat, user, chars, quotas)
+                .unwrap()
+        };
+        assert_eq!(reserve(Purpose::Auto, 50), None);
+        assert_eq!(reserve(Purpose::Auto, 1), Some(Exceeded::DailyQuota));
+        assert_eq!(reserve(Purpose::Command, 50), None);
+        assert_eq!(reserve(Purpose::Command, 1), Some(Exceeded::DailyQuota));
+    }
+
+    #[test]
+    fn drops_full_buckets_when_there_are_too_many() {
+        let limiter = RateLimiter::new(1);
+        let now = Instant::now();
+        limiter.try_acquire_at(0, now).unwrap();
+        {
+            let mut buckets = limiter.buckets.lock().unwrap();
+            for key in 1..MAX_BUCKETS {
+                buckets.insert(key, (1.0, now));
+            }
+        }
+        limiter.try_acquire_at(MAX_BUCKETS, now).unwrap();
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert!(buckets.contains_key(&0));
+    }
+
+    #[test]
+    fn runtime_and_private_chats_get_the_default_quotas() {
+        let storage = Storage::in_memory().unwrap();
+        let config = Config::new(
+            String::new().into(),
+            String::new().into(),
+            Vec::new(),
+            None,
+            None,
+            false,
+        )
+        .set_default_char_quotas(100, 0);
+        let auth = crate::Auth::new(&config, storage.clone()).unwrap();
+        auth.allow(ChatId(-5), "new", UserId(1)).unwrap();
+        let limits = Limits::new(&config, storage);
+
+        for chat in [ChatId(-5), ChatId(1)] {
+            let quotas = auth.char_quotas(&chat);
+            let reserve = |chars| {
+                limits
+                    .reserve(
+                        Purpose::Command,
+                        "test",
+                        chat,
+                        None,
+                        chars,
+                        quotas,
+                    )
+                    .unwrap()
+            };
+            assert_eq!(reserve(100), None);
+            assert_eq!(reserve(1), Some(Exceeded::DailyQuota));
+        }
+    }
+}
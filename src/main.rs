@@ -1,8 +1,81 @@
 use std::sync::Arc;
 use teloxide::prelude::*;
+use teloxide::stop::mk_stop_token;
 
 use hilfmir::webhook;
-use hilfmir::{handle_command, load_config, Auth, Command, GoogleCloudClient};
+use hilfmir::{
+    build_config, handle_auto_translate, handle_command, handle_edited_message,
+    handle_forbidden_command, handle_my_chat_member, handle_settings_callback,
+    handle_unauthorized_message, publish_commands, shutdown_signal,
+    spawn_server, watch_config, AppError, Auth, Catalog, Command, Config,
+    ConfigLayers, Health, Limits, Reloader, State, Storage,
+};
+
+const USAGE: &str = "Usage: hilfmir [publish-commands] [--print-config] [--<setting> <value>]...
+
+Commands:
+    publish-commands      Publish the command menu to Telegram and exit
+
+Options:
+    --print-config        Print every setting, its value and where it comes from, and exit
+    --<setting> <value>   Override a setting of the config file and the environment,
+                          e.g. --bind-port 8081 for BIND_PORT";
+
+/// The parsed command line.
+struct Args {
+    subcommand: Option<String>,
+    print_config: bool,
+    /// Settings given as `--name value` or `--name=value`.
+    flags: Vec<(String, String)>,
+}
+
+/// Parses the command line, or returns `None` if it isn't understood.
+fn parse_args(mut args: impl Iterator<Item = String>) -> Option<Args> {
+    let mut parsed = Args {
+        subcommand: None,
+        print_config: false,
+        flags: vec![],
+    };
+    while let Some(arg) = args.next() {
+        match arg.strip_prefix("--") {
+            Some("print-config") => parsed.print_config = true,
+            Some(flag) => {
+                let (name, value) = match flag.split_once('=') {
+                    Some((name, value)) => {
+                        (name.to_string(), value.to_string())
+                    }
+                    None => (flag.to_string(), args.next()?),
+                };
+                parsed.flags.push((name, value));
+            }
+            None if parsed.subcommand.is_none() => {
+                parsed.subcommand = Some(arg)
+            }
+            None => return None,
+        }
+    }
+    Some(parsed)
+}
+
+/// Loads the config, or reports every problem with it and exits.
+fn load_config_or_exit(flags: &[(String, String)]) -> (ConfigLayers, Config) {
+    let layers = ConfigLayers::load(|name| std::env::var(name).ok(), flags);
+    match build_config(&layers) {
+        Ok(config) => (layers, config),
+        Err(err) => {
+            log::error!("{err}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Opens the database, or reports why it can't and exits.
+fn open_storage_or_exit(config: &Config) -> Storage {
+    Storage::open(&config.database_path).unwrap_or_else(|err| {
+        log::error!("Cannot open the database {}: {err}", config.database_path);
+        std::process::exit(1);
+    })
+}
 
 #[tokio::main]
 async fn main() {
@@ -10,47 +83,235 @@ async fn main() {
     // tracing_subscriber::fmt::init(); // using pretty_env_logger instead for now
     pretty_env_logger::init();
 
-    log::info!("Starting Hilfmir bot...");
-
-    let config = Arc::new(load_config());
-    let auth = Arc::new(Auth::new(&config));
+    let args = parse_args(std::env::args().skip(1)).unwrap_or_else(|| {
+        eprintln!("{USAGE}");
+        std::process::exit(2);
+    });
+    if args.print_config {
+        let layers =
+            ConfigLayers::load(|name| std::env::var(name).ok(), &args.flags);
+        println!("{}", layers.describe());
+        if let Err(err) = build_config(&layers) {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    match args.subcommand.as_deref() {
+        None => {}
+        Some("publish-commands") => {
+            let (_, config) = load_config_or_exit(&args.flags);
+            let storage = open_storage_or_exit(&config);
+            let auth = Auth::new(&config, storage).unwrap_or_else(|err| {
+                log::error!("Cannot load the allow-list: {err}");
+                std::process::exit(1);
+            });
+            let bot = Bot::new(config.teloxide_token.expose_secret());
+            if let Err(err) =
+                publish_commands(&bot, &Catalog::new(), &auth).await
+            {
+                log::error!("Failed to publish the command menu: {err}");
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(_) => {
+            eprintln!("{USAGE}");
+            std::process::exit(2);
+        }
+    }
 
-    let google_cloud_client =
-        Arc::new(GoogleCloudClient::new(config.google_cloud_api_key.clone()));
+    log::info!("Starting Hilfmir bot...");
 
+    let (layers, config) = load_config_or_exit(&args.flags);
+    let storage = open_storage_or_exit(&config);
     let bot = Bot::new(config.teloxide_token.expose_secret());
+    let is_webhook_mode_enabled = config.is_webhook_mode_enabled;
+    let limits = Arc::new(Limits::new(&config, storage.clone()));
+    let reloader = Arc::new(
+        Reloader::new(
+            layers,
+            config,
+            args.flags,
+            storage.clone(),
+            limits.clone(),
+        )
+        .unwrap_or_else(|err| {
+            log::error!("Cannot load the allow-list: {err}");
+            std::process::exit(1);
+        }),
+    );
+    let catalog = Arc::new(Catalog::new());
 
-    let handler = Update::filter_message().branch(
-        dptree::filter(|msg: Message, auth: Arc<Auth>| {
-            auth.message_is_authorized(msg)
-        })
-        .filter_command::<Command>()
-        .endpoint(handle_command),
+    if let Err(err) =
+        publish_commands(&bot, &catalog, &reloader.state().auth).await
+    {
+        log::error!("Failed to publish the command menu: {err}");
+    }
+    tokio::spawn(watch_config(reloader.clone(), bot.clone(), catalog.clone()));
+
+    let commands = Update::filter_message().filter_command::<Command>().branch(
+        dptree::filter_async(
+            |bot: Bot, msg: Message, cmd: Command, auth: Arc<Auth>| async move {
+                cmd.is_available_to_unauthorized(&auth, &msg)
+                    || auth.message_is_authorized(&bot, &msg).await
+            },
+        )
+        .branch(
+            dptree::filter_async(
+                |bot: Bot,
+                 msg: Message,
+                 cmd: Command,
+                 auth: Arc<Auth>| async move {
+                    auth.has_role(&bot, &msg, cmd.required_role()).await
+                },
+            )
+            .endpoint(handle_command),
+        )
+        .branch(dptree::endpoint(handle_forbidden_command)),
+    );
+    let edited_messages = Update::filter_edited_message().branch(
+        dptree::filter_async(
+            |bot: Bot, msg: Message, auth: Arc<Auth>| async move {
+                auth.message_is_authorized(&bot, &msg).await
+            },
+        )
+        .endpoint(handle_edited_message),
     );
+    let messages = Update::filter_message()
+        .branch(
+            dptree::filter_async(
+                |bot: Bot, msg: Message, auth: Arc<Auth>| async move {
+                    auth.message_is_authorized(&bot, &msg).await
+                },
+            )
+            .endpoint(handle_auto_translate),
+        )
+        .branch(dptree::endpoint(handle_unauthorized_message));
+    // Every update is handled with the state current when it arrived, even
+    // if the config is reloaded in the meantime.
+    let handler = dptree::entry()
+        .map(|reloader: Arc<Reloader>| reloader.state())
+        .map(|state: Arc<State>| state.config.clone())
+        .map(|state: Arc<State>| state.auth.clone())
+        .map(|state: Arc<State>| state.google_cloud_client.clone())
+        .branch(commands)
+        .branch(edited_messages)
+        .branch(messages)
+        .branch(
+            Update::filter_callback_query().endpoint(handle_settings_callback),
+        )
+        .branch(
+            Update::filter_my_chat_member().endpoint(handle_my_chat_member),
+        );
 
     let mut bot_dispatcher = Dispatcher::builder(bot.clone(), handler)
         // Pass the shared state to the handler as a dependency.
         .dependencies(dptree::deps![
-            config.clone(),
-            auth,
-            google_cloud_client.clone()
+            reloader.clone(),
+            catalog,
+            limits,
+            Arc::new(storage.clone())
         ])
-        .enable_ctrlc_handler()
         .build();
+    let shutdown_token = bot_dispatcher.shutdown_token();
+    let webhook_url = reloader
+        .state()
+        .config
+        .webhook
+        .as_ref()
+        .and_then(|webhook| webhook.url.parse().ok());
+    let health = Arc::new(Health::new(bot.clone(), webhook_url));
 
-    if config.is_webhook_mode_enabled {
-        log::info!("Webhook mode activated");
-        let rx = webhook(config, bot);
-        bot_dispatcher
-            .dispatch_with_listener(
-                rx.await,
-                LoggingErrorHandler::with_custom_text(
-                    "An error from the update listener",
-                ),
+    // Ends with the error that stopped the server, if any.
+    let dispatching = async {
+        if is_webhook_mode_enabled {
+            log::info!("Webhook mode activated");
+            let (rx, server) = webhook(
+                reloader.clone(),
+                bot.clone(),
+                storage.clone(),
+                health.clone(),
             )
-            .await;
-    } else {
-        log::info!("Long polling mode activated");
-        bot_dispatcher.dispatch().await;
+            .await?;
+            bot_dispatcher
+                .dispatch_with_listener(
+                    rx,
+                    LoggingErrorHandler::with_custom_text(
+                        "An error from the update listener",
+                    ),
+                )
+                .await;
+            // The updates also stop when the server fails.
+            server.await?
+        } else {
+            log::info!("Long polling mode activated");
+            // Serves the usage report and the probes, if asked to, until
+            // the updates are handled.
+            let (stop_token, stop_flag) = mk_stop_token();
+            let server = match reloader.state().config.listen {
+                Some(_) => Some(
+                    spawn_server(
+                        axum::Router::new(),
+                        reloader.clone(),
+                        storage.clone(),
+                        health.clone(),
+                        stop_flag,
+                    )
+                    .await?,
+                ),
+                None => None,
+            };
+            bot_dispatcher.dispatch().await;
+            stop_token.stop();
+            match server {
+                Some(server) => server.await?,
+                None => Ok(()),
+            }
+        }
+    };
+    tokio::pin!(dispatching);
+
+    let result: Result<(), AppError> = tokio::select! {
+        result = &mut dispatching => result,
+        _ = shutdown_signal() => {
+            // Stops the update listener, and with it the webhook server.
+            // The dispatcher then finishes the updates it already has.
+            match shutdown_token.shutdown() {
+                Ok(_) => {
+                    let timeout = reloader.state().config.shutdown_timeout();
+                    tokio::time::timeout(timeout, dispatching)
+                        .await
+                        .unwrap_or_else(|_| {
+                            log::warn!(
+                                "Updates still in flight after {}s, exiting \
+                                 anyway",
+                                timeout.as_secs()
+                            );
+                            Ok(())
+                        })
+                }
+                Err(_) => {
+                    log::info!("Not dispatching yet, nothing to finish");
+                    Ok(())
+                }
+            }
+        }
+    };
+
+    let config = reloader.state().config.clone();
+    if is_webhook_mode_enabled && config.delete_webhook_on_shutdown {
+        match bot.delete_webhook().await {
+            Ok(_) => log::info!("Deleted the webhook"),
+            Err(err) => log::error!("Cannot delete the webhook: {err}"),
+        }
+    }
+    if let Err(err) = storage.optimize() {
+        log::error!("Cannot optimize the database: {err}");
+    }
+    if let Err(err) = result {
+        log::error!("Hilfmir bot failed: {err}");
+        std::process::exit(1);
     }
+    log::info!("Hilfmir bot stopped");
 }
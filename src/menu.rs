@@ -0,0 +1,67 @@
+use teloxide::prelude::*;
+use teloxide::types::{BotCommandScope, Recipient};
+
+use crate::auth::Role;
+use crate::commands::localized_bot_commands;
+use crate::i18n::Catalog;
+use crate::language::Language;
+use crate::Auth;
+
+/// Scopes the command menu is published for, with the role whose commands
+/// are shown in each.
+const GENERAL_SCOPES: [(BotCommandScope, Role); 3] = [
+    (BotCommandScope::AllPrivateChats, Role::Admin),
+    (BotCommandScope::AllGroupChats, Role::Member),
+    (BotCommandScope::AllChatAdministrators, Role::Admin),
+];
+
+/// Publishes the "/" command menu with `setMyCommands` for every scope, once
+/// per UI language plus an English default for clients in other languages.
+///
+/// Owners get their own menu in their private chat. Telegram refuses it for
+/// owners who never started a chat with the bot, which is only logged, so
+/// that the menu of everyone else is still published.
+pub async fn publish_commands(
+    bot: &Bot,
+    catalog: &Catalog,
+    auth: &Auth,
+) -> crate::Result<()> {
+    for (scope, role) in GENERAL_SCOPES {
+        publish_scope(bot, catalog, scope, role).await?;
+    }
+    for owner in auth.owners() {
+        let chat_id = Recipient::Id(ChatId(owner.0 as i64));
+        let scope = BotCommandScope::Chat { chat_id };
+        if let Err(err) = publish_scope(bot, catalog, scope, Role::Owner).await
+        {
+            log::warn!("Cannot publish the commands of owner [{owner}]: {err}");
+        }
+    }
+
+    log::info!("Published the command menu");
+    Ok(())
+}
+
+async fn publish_scope(
+    bot: &Bot,
+    catalog: &Catalog,
+    scope: BotCommandScope,
+    role: Role,
+) -> crate::Result<()> {
+    bot.set_my_commands(localized_bot_commands(
+        catalog,
+        Language::English,
+        role,
+    ))
+    .scope(scope.clone())
+    .await?;
+
+    for lang in Language::ALL {
+        bot.set_my_commands(localized_bot_commands(catalog, lang, role))
+            .scope(scope.clone())
+            .language_code(lang.code())
+            .await?;
+    }
+    log::debug!("Published commands for scope {:?}", scope);
+    Ok(())
+}
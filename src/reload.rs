@@ -0,0 +1,179 @@
+use std::env::var;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use teloxide::prelude::*;
+
+use crate::config::{build_config, ConfigLayers};
+use crate::i18n::Catalog;
+use crate::limits::Limits;
+use crate::menu::publish_commands;
+use crate::storage::Storage;
+use crate::{Auth, Config, GoogleCloudClient};
+
+/// How often the config file is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The parts of the bot built from the config, replaced together on reload.
+pub struct State {
+    pub config: Arc<Config>,
+    pub auth: Arc<Auth>,
+    pub google_cloud_client: Arc<GoogleCloudClient>,
+}
+
+impl State {
+    fn new(config: Config, storage: &Storage) -> crate::Result<Self> {
+        let auth = Auth::new(&config, storage.clone())?;
+        let google_cloud_client =
+            GoogleCloudClient::new(config.google_cloud_api_key.clone());
+        Ok(Self {
+            config: Arc::new(config),
+            auth: Arc::new(auth),
+            google_cloud_client: Arc::new(google_cloud_client),
+        })
+    }
+
+    /// The state of a reloaded config. The new `Auth` keeps the runtime
+    /// state of the current one.
+    fn reload(&self, config: Config) -> Self {
+        let auth = self.auth.reload(&config);
+        let google_cloud_client =
+            GoogleCloudClient::new(config.google_cloud_api_key.clone());
+        Self {
+            config: Arc::new(config),
+            auth: Arc::new(auth),
+            google_cloud_client: Arc::new(google_cloud_client),
+        }
+    }
+}
+
+/// Holds the current `State`, and rebuilds it when the config changes.
+/// Updates being handled keep the state they started with.
+pub struct Reloader {
+    state: RwLock<Arc<State>>,
+    layers: Mutex<ConfigLayers>,
+    /// The command line flags, which stay the same across reloads.
+    flags: Vec<(String, String)>,
+    /// Kept across reloads, which only change the limits.
+    limits: Arc<Limits>,
+}
+
+impl Reloader {
+    pub fn new(
+        layers: ConfigLayers,
+        config: Config,
+        flags: Vec<(String, String)>,
+        storage: Storage,
+        limits: Arc<Limits>,
+    ) -> crate::Result<Self> {
+        Ok(Self {
+            state: RwLock::new(Arc::new(State::new(config, &storage)?)),
+            layers: Mutex::new(layers),
+            flags,
+            limits,
+        })
+    }
+
+    pub fn state(&self) -> Arc<State> {
+        self.state.read().expect("state lock poisoned").clone()
+    }
+
+    fn config_modified(&self) -> Option<SystemTime> {
+        let layers = self.layers.lock().expect("config layers poisoned");
+        std::fs::metadata(layers.config_path())
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    /// Reads the config again and, if it's valid and changed, swaps in a
+    /// state built from it. Returns whether it changed. Blocks while reading
+    /// the files and resolving host names.
+    pub fn reload(&self) -> crate::Result<bool> {
+        let new_layers = ConfigLayers::load(|name| var(name).ok(), &self.flags);
+        let config = build_config(&new_layers)?;
+
+        let mut layers = self.layers.lock().expect("config layers poisoned");
+        let changes = layers.diff(&new_layers);
+        if changes.is_empty() {
+            log::info!("The config is unchanged");
+            return Ok(false);
+        }
+
+        let state = self.state().reload(config);
+        self.limits.reconfigure(&state.config);
+        *self.state.write().expect("state lock poisoned") = Arc::new(state);
+        *layers = new_layers;
+        for change in changes {
+            log::info!("Reloaded config: {change}");
+        }
+        Ok(true)
+    }
+}
+
+/// Reloads the config on SIGHUP and when the config file is modified, and
+/// republishes the command menu after a change.
+pub async fn watch_config(
+    reloader: Arc<Reloader>,
+    bot: Bot,
+    catalog: Arc<Catalog>,
+) {
+    let mut hangups = Hangups::new();
+    let mut interval = tokio::time::interval(WATCH_INTERVAL);
+    let mut modified = reloader.config_modified();
+    loop {
+        tokio::select! {
+            _ = hangups.recv() => log::info!("Reloading the config on SIGHUP"),
+            _ = interval.tick() => {
+                let now = reloader.config_modified();
+                if now == modified {
+                    continue;
+                }
+                modified = now;
+                log::info!("The config file changed, reloading it");
+            }
+        }
+
+        // Reading the config blocks, on files and on resolving the host
+        // name in `BIND_ADDRESS`.
+        let reloading = reloader.clone();
+        let reloaded = tokio::task::spawn_blocking(move || reloading.reload())
+            .await
+            .map_err(crate::AppError::from)
+            .and_then(|reloaded| reloaded);
+        match reloaded {
+            Ok(true) => {
+                let auth = reloader.state().auth.clone();
+                if let Err(err) = publish_commands(&bot, &catalog, &auth).await
+                {
+                    log::error!("Failed to publish the command menu: {err}");
+                }
+            }
+            Ok(false) => {}
+            Err(err) => log::error!("Keeping the current config. {err}"),
+        }
+    }
+}
+
+/// The SIGHUP signals received, on platforms that have them.
+struct Hangups {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+}
+
+impl Hangups {
+    fn new() -> Self {
+        Self {
+            #[cfg(unix)]
+            signal: tokio::signal::unix::signal(
+                tokio::signal::unix::SignalKind::hangup(),
+            )
+            .expect("Cannot listen for SIGHUP"),
+        }
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        self.signal.recv().await;
+        #[cfg(not(unix))]
+        std::future::pending::<()>().await;
+    }
+}
@@ -0,0 +1,251 @@
+//! Offline romanization of non-Latin scripts: Hangul to the Revised
+//! Romanization of Korean and Cyrillic to ISO 9.
+
+const HANGUL_FIRST: u32 = 0xAC00;
+const HANGUL_LAST: u32 = 0xD7A3;
+const MEDIALS_COUNT: u32 = 21;
+const FINALS_COUNT: u32 = 28;
+
+const INITIALS: [&str; 19] = [
+    "g", "kk", "n", "d", "tt", "r", "m", "b", "pp", "s", "ss", "", "j", "jj",
+    "ch", "k", "t", "p", "h",
+];
+
+const MEDIALS: [&str; 21] = [
+    "a", "ae", "ya", "yae", "eo", "e", "yeo", "ye", "o", "wa", "wae", "oe",
+    "yo", "u", "wo", "we", "wi", "yu", "eu", "ui", "i",
+];
+
+/// Finals at the end of a word or before another consonant.
+const FINALS: [&str; 28] = [
+    "", "k", "k", "k", "n", "n", "n", "t", "l", "k", "m", "l", "l", "l", "p",
+    "l", "m", "p", "p", "t", "t", "ng", "t", "t", "k", "t", "p", "t",
+];
+
+/// Finals followed by a syllable starting with the silent ㅇ, where the
+/// consonant carries over to the next syllable (e.g. 한국어 → hangugeo).
+/// Clusters keep their first consonant and carry over the second one.
+const FINALS_BEFORE_VOWEL: [(&str, &str); 28] = [
+    ("", ""),
+    ("", "g"),
+    ("", "kk"),
+    ("k", "s"),
+    ("", "n"),
+    ("n", "j"),
+    ("n", ""),
+    ("", "d"),
+    ("", "r"),
+    ("l", "g"),
+    ("l", "m"),
+    ("l", "b"),
+    ("l", "s"),
+    ("l", "t"),
+    ("l", "p"),
+    ("", "r"),
+    ("", "m"),
+    ("", "b"),
+    ("p", "s"),
+    ("", "s"),
+    ("", "ss"),
+    ("ng", ""),
+    ("", "j"),
+    ("", "ch"),
+    ("", "k"),
+    ("", "t"),
+    ("", "p"),
+    ("", ""),
+];
+
+const SILENT_INITIAL: usize = 11;
+const NIEUN_INITIAL: usize = 2;
+const RIEUL_INITIAL: usize = 5;
+const NIEUN_FINAL: usize = 4;
+const RIEUL_FINAL: usize = 8;
+
+/// Indices of the initial, medial and final jamo of a Hangul syllable.
+fn decompose(c: char) -> Option<(usize, usize, usize)> {
+    let code = c as u32;
+    if !(HANGUL_FIRST..=HANGUL_LAST).contains(&code) {
+        return None;
+    }
+    let index = code - HANGUL_FIRST;
+    Some((
+        (index / (MEDIALS_COUNT * FINALS_COUNT)) as usize,
+        ((index % (MEDIALS_COUNT * FINALS_COUNT)) / FINALS_COUNT) as usize,
+        (index % FINALS_COUNT) as usize,
+    ))
+}
+
+fn cyrillic(c: char) -> Option<&'static str> {
+    let latin = match c {
+        'а' => "a",
+        'б' => "b",
+        'в' => "v",
+        'г' => "g",
+        'д' => "d",
+        'е' => "e",
+        'ё' => "ë",
+        'ж' => "ž",
+        'з' => "z",
+        'и' => "i",
+        'й' => "j",
+        'к' => "k",
+        'л' => "l",
+        'м' => "m",
+        'н' => "n",
+        'о' => "o",
+        'п' => "p",
+        'р' => "r",
+        'с' => "s",
+        'т' => "t",
+        'у' => "u",
+        'ф' => "f",
+        'х' => "h",
+        'ц' => "c",
+        'ч' => "č",
+        'ш' => "š",
+        'щ' => "ŝ",
+        'ъ' => "ʺ",
+        'ы' => "y",
+        'ь' => "ʹ",
+        'э' => "è",
+        'ю' => "û",
+        'я' => "â",
+        'і' => "ì",
+        'ї' => "ï",
+        'є' => "ê",
+        'ґ' => "g̀",
+        'ў' => "ǔ",
+        _ => return None,
+    };
+    Some(latin)
+}
+
+fn is_cyrillic(c: char) -> bool {
+    c.to_lowercase().next().and_then(cyrillic).is_some()
+}
+
+/// Whether `text` contains Hangul or Cyrillic that `romanize` would change.
+pub fn has_romanizable_script(text: &str) -> bool {
+    text.chars()
+        .any(|c| decompose(c).is_some() || is_cyrillic(c))
+}
+
+/// Romanizes the Hangul and Cyrillic in `text`, leaving other characters
+/// untouched.
+pub fn romanize(text: &str) -> String {
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut out = String::with_capacity(text.len());
+    // The consonant carried over from the previous syllable, if any.
+    let mut carried: Option<&str> = None;
+
+    for (i, &c) in chars.iter().enumerate() {
+        if let Some((initial, medial, last)) = decompose(c) {
+            let next = chars.get(i + 1).copied().and_then(decompose);
+            let initial = match carried.take() {
+                Some(consonant) => consonant,
+                None => INITIALS[initial],
+            };
+            out.push_str(initial);
+            out.push_str(MEDIALS[medial]);
+
+            match next {
+                Some((SILENT_INITIAL, _, _)) => {
+                    let (keep, carry) = FINALS_BEFORE_VOWEL[last];
+                    out.push_str(keep);
+                    carried = (!carry.is_empty()).then_some(carry);
+                }
+                // ㄹ next to ㄴ or ㄹ is read ll (설날 → seollal,
+                // 신라 → silla).
+                Some((RIEUL_INITIAL, _, _))
+                    if last == RIEUL_FINAL || last == NIEUN_FINAL =>
+                {
+                    out.push('l');
+                    carried = Some("l");
+                }
+                Some((NIEUN_INITIAL, _, _)) if last == RIEUL_FINAL => {
+                    out.push('l');
+                    carried = Some("l");
+                }
+                _ => out.push_str(FINALS[last]),
+            }
+        } else if let Some(latin) = c.to_lowercase().next().and_then(cyrillic) {
+            carried = None;
+            if c.is_uppercase() {
+                out.push_str(&latin.to_uppercase());
+            } else {
+                out.push_str(latin);
+            }
+        } else {
+            carried = None;
+            out.push(c);
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn romanizes_hangul() {
+        assert_eq!(romanize("한국어"), "hangugeo");
+        assert_eq!(romanize("안녕하세요"), "annyeonghaseyo");
+        assert_eq!(romanize("서울"), "seoul");
+    }
+
+    #[test]
+    fn carries_finals_over_to_a_following_vowel() {
+        assert_eq!(romanize("음악"), "eumak");
+        assert_eq!(romanize("좋아요"), "joayo");
+        // Clusters keep their first consonant and carry over the second.
+        assert_eq!(romanize("읽어"), "ilgeo");
+        assert_eq!(romanize("값이"), "gapsi");
+    }
+
+    #[test]
+    fn assimilates_nieun_and_rieul() {
+        assert_eq!(romanize("설날"), "seollal");
+        assert_eq!(romanize("달력"), "dallyeok");
+        assert_eq!(romanize("신라"), "silla");
+    }
+
+    #[test]
+    fn does_not_carry_over_across_words() {
+        assert_eq!(romanize("밥 아니"), "bap ani");
+    }
+
+    #[test]
+    fn romanizes_cyrillic() {
+        assert_eq!(romanize("привет"), "privet");
+        assert_eq!(romanize("щука ёж"), "ŝuka ëž");
+        assert_eq!(romanize("їжак ґанок"), "ïžak g̀anok");
+    }
+
+    #[test]
+    fn keeps_cyrillic_capitals() {
+        assert_eq!(romanize("Москва"), "Moskva");
+        assert_eq!(romanize("ЖУК"), "ŽUK");
+    }
+
+    #[test]
+    fn romanizes_mixed_scripts() {
+        assert_eq!(romanize("Привет, 세계!"), "Privet, segye!");
+        assert_eq!(romanize("Kim 김치 2"), "Kim gimchi 2");
+    }
+
+    #[test]
+    fn leaves_latin_text_alone() {
+        assert_eq!(romanize("Hallo Welt!"), "Hallo Welt!");
+        assert!(!has_romanizable_script("Hallo Welt!"));
+        assert!(!has_romanizable_script("日本語"));
+    }
+
+    #[test]
+    fn detects_romanizable_scripts() {
+        assert!(has_romanizable_script("hi 안녕"));
+        assert!(has_romanizable_script("Да"));
+    }
+}
@@ -0,0 +1,162 @@
+use axum::extract::{Extension, Query};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::routing::get;
+use axum::Json;
+use axum_server::tls_rustls::RustlsConfig;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::Deserialize;
+use std::sync::Arc;
+use teloxide::stop::StopFlag;
+use tokio::task::JoinHandle;
+
+use crate::config::{Listen, Tls};
+use crate::health::{healthz, readyz, Health};
+use crate::storage::{this_month, Storage};
+use crate::usage::{parse_month, UsageReport};
+use crate::Reloader;
+
+/// Serves `routes` along with the usage report and the health probes,
+/// which are there in both modes, until `stop` is raised. Fails if the
+/// server can't listen, and otherwise returns the task serving the
+/// requests, which ends with the error that stopped the server, if any.
+pub async fn spawn_server(
+    routes: axum::Router,
+    reloader: Arc<Reloader>,
+    storage: Storage,
+    health: Arc<Health>,
+    stop: StopFlag,
+) -> crate::Result<JoinHandle<crate::Result<()>>> {
+    let config = reloader.state().config.clone();
+    let app = routes
+        .route("/", get(root))
+        .route("/usage", get(usage))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .layer(
+            tower::ServiceBuilder::new()
+                .layer(tower_http::trace::TraceLayer::new_for_http())
+                .layer(tower_http::add_extension::AddExtensionLayer::new(
+                    reloader,
+                ))
+                .layer(tower_http::add_extension::AddExtensionLayer::new(
+                    storage,
+                ))
+                .layer(tower_http::add_extension::AddExtensionLayer::new(
+                    health,
+                ))
+                .into_inner(),
+        );
+
+    let listen = config.listen.clone().ok_or_else(|| crate::AppError {
+        msg: "BIND_ADDRESS is not set".to_string(),
+    })?;
+    let server = bind(app, listen, config.tls.clone(), stop).await?;
+    Ok(tokio::spawn(server))
+}
+
+#[derive(Debug, Deserialize)]
+struct UsageQuery {
+    month: Option<String>,
+}
+
+/// The monthly usage report as JSON, for the holders of the usage API
+/// token, if there is one. Defaults to the current month.
+async fn usage(
+    Extension(reloader): Extension<Arc<Reloader>>,
+    Extension(storage): Extension<Storage>,
+    Query(query): Query<UsageQuery>,
+    headers: HeaderMap,
+) -> Result<Json<UsageReport>, StatusCode> {
+    let config = reloader.state().config.clone();
+    let token = match &config.usage_api_token {
+        Some(token) => token,
+        None => return Err(StatusCode::NOT_FOUND),
+    };
+    let given = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !given.is_some_and(|given| token.matches(given)) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let month = match query.month {
+        Some(month) => parse_month(&month).ok_or(StatusCode::BAD_REQUEST)?,
+        None => this_month(),
+    };
+    UsageReport::for_month(&storage, &config.prices, &month)
+        .map(Json)
+        .map_err(|err| {
+            log::error!("Cannot build the usage report: {err}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+/// Listens on a TCP address, with TLS if configured, or a Unix socket, and
+/// returns the server, which serves `app` until `stop` is raised, then
+/// finishes the requests in flight.
+async fn bind(
+    app: axum::Router,
+    listen: Listen,
+    tls: Option<Tls>,
+    stop: StopFlag,
+) -> crate::Result<BoxFuture<'static, crate::Result<()>>> {
+    let service = app.into_make_service();
+    match (listen, tls) {
+        (Listen::Tcp(address), Some(tls)) => {
+            let rustls_config =
+                RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path)
+                    .await?;
+            let listener = std::net::TcpListener::bind(address)?;
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    stop.await;
+                    handle.graceful_shutdown(None);
+                }
+            });
+            tracing::debug!("server listening on {} with TLS", address);
+            let server = axum_server::from_tcp_rustls(listener, rustls_config)
+                .handle(handle)
+                .serve(service);
+            Ok(async move { Ok(server.await?) }.boxed())
+        }
+        (Listen::Tcp(address), None) => {
+            let server = axum::Server::try_bind(&address)?
+                .serve(service)
+                .with_graceful_shutdown(stop);
+            tracing::debug!("server listening on {}", address);
+            Ok(async move { Ok(server.await?) }.boxed())
+        }
+        #[cfg(unix)]
+        (Listen::Unix(path), _) => {
+            use std::os::unix::fs::FileTypeExt;
+            // A socket left behind by an earlier run would fail the bind.
+            if std::fs::metadata(&path)
+                .is_ok_and(|metadata| metadata.file_type().is_socket())
+            {
+                std::fs::remove_file(&path)?;
+            }
+            let listener = tokio::net::UnixListener::bind(&path)?;
+            tracing::debug!("server listening on {}", path.display());
+            let incoming =
+                tokio_stream::wrappers::UnixListenerStream::new(listener);
+            let server = axum::Server::builder(
+                hyper::server::accept::from_stream(incoming),
+            )
+            .serve(service)
+            .with_graceful_shutdown(stop);
+            Ok(async move { Ok(server.await?) }.boxed())
+        }
+        #[cfg(not(unix))]
+        (Listen::Unix(_), _) => Err(crate::AppError {
+            msg: "Unix sockets aren't supported here".to_string(),
+        }),
+    }
+}
+
+async fn root() -> &'static str {
+    "ok"
+}
@@ -0,0 +1,322 @@
+use std::sync::Arc;
+use std::time::Duration;
+use teloxide::prelude::*;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
+use teloxide::{ApiError, RequestError};
+
+use crate::auth::Role;
+use crate::i18n::{ui_language_for, Catalog};
+use crate::language::Language;
+use crate::storage::Storage;
+use crate::Auth;
+
+const CALLBACK_PREFIX: &str = "settings:";
+/// The choices of how many days to keep the history for, after "off".
+const HISTORY_DAYS: [u32; 3] = [7, 30, 90];
+
+/// How translations are laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// The flags of the source and target languages above the translation.
+    Flags,
+    /// Just the translation.
+    Plain,
+}
+
+impl OutputFormat {
+    fn code(&self) -> &'static str {
+        match self {
+            OutputFormat::Flags => "flags",
+            OutputFormat::Plain => "plain",
+        }
+    }
+
+    fn parse(code: &str) -> Option<Self> {
+        match code {
+            "flags" => Some(OutputFormat::Flags),
+            "plain" => Some(OutputFormat::Plain),
+            _ => None,
+        }
+    }
+}
+
+/// The settings in effect in a chat: as changed with `/settings`, else as
+/// in the config, else the defaults.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Translated into when no language is given, and by auto mode.
+    pub default_target: Option<Language>,
+    /// Translate every message into the default target.
+    pub auto_translate: bool,
+    pub output_format: OutputFormat,
+    /// Repeat the original text in translations.
+    pub show_original: bool,
+    /// Append the romanized original text to translations.
+    pub romanize: bool,
+    /// Days translations are kept in the history for, 0 to keep none.
+    pub history_days: u32,
+    /// Language of the bot's own messages in the chat, unless it follows
+    /// the config and the users.
+    pub ui_language: Option<Language>,
+}
+
+impl Settings {
+    pub fn load(
+        auth: &Auth,
+        storage: &Storage,
+        chat_id: ChatId,
+    ) -> crate::Result<Self> {
+        let stored = storage.chat_settings().get(chat_id)?;
+        Ok(Self {
+            default_target: stored
+                .default_target
+                .as_deref()
+                .and_then(Language::parse_code),
+            auto_translate: stored.auto_translate.unwrap_or(false),
+            output_format: stored
+                .output_format
+                .as_deref()
+                .and_then(OutputFormat::parse)
+                .unwrap_or(OutputFormat::Flags),
+            show_original: stored.show_original.unwrap_or(false),
+            romanize: stored
+                .romanize
+                .unwrap_or_else(|| auth.romanize_by_default(&chat_id)),
+            history_days: stored.history_days.unwrap_or(0),
+            ui_language: stored
+                .ui_language
+                .as_deref()
+                .and_then(Language::parse_code),
+        })
+    }
+}
+
+/// The buttons of the settings menu.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Target,
+    Auto,
+    Format,
+    Original,
+    Romanize,
+    History,
+    UiLanguage,
+    Done,
+}
+
+impl Field {
+    const ALL: [Field; 8] = [
+        Field::Target,
+        Field::Auto,
+        Field::Format,
+        Field::Original,
+        Field::Romanize,
+        Field::History,
+        Field::UiLanguage,
+        Field::Done,
+    ];
+
+    fn code(&self) -> &'static str {
+        match self {
+            Field::Target => "target",
+            Field::Auto => "auto",
+            Field::Format => "format",
+            Field::Original => "original",
+            Field::Romanize => "romanize",
+            Field::History => "history",
+            Field::UiLanguage => "ui-language",
+            Field::Done => "done",
+        }
+    }
+
+    fn parse(code: &str) -> Option<Self> {
+        Field::ALL.into_iter().find(|field| field.code() == code)
+    }
+}
+
+/// The language after `lang` in the menu's cycle, which includes none.
+fn next_language(lang: Option<Language>) -> Option<Language> {
+    let next = match lang {
+        Some(lang) => {
+            Language::ALL.iter().position(|l| *l == lang).unwrap() + 1
+        }
+        None => 0,
+    };
+    Language::ALL.get(next).copied()
+}
+
+/// The number of days after `days` in the menu's cycle, which includes 0.
+fn next_history_days(days: u32) -> u32 {
+    HISTORY_DAYS
+        .into_iter()
+        .find(|choice| *choice > days)
+        .unwrap_or(0)
+}
+
+/// The settings menu: a description of the settings, and a button per
+/// setting showing its value, which changes it when tapped.
+pub fn settings_menu(
+    catalog: &Catalog,
+    lang: Language,
+    settings: &Settings,
+) -> (String, InlineKeyboardMarkup) {
+    let on_off = |value: bool| match value {
+        true => catalog.tr(lang, "settings-on"),
+        false => catalog.tr(lang, "settings-off"),
+    };
+    let buttons = Field::ALL.into_iter().map(|field| {
+        let value = match field {
+            Field::Target => match settings.default_target {
+                Some(target) => format!("{} {}", target.emoji(), target.code()),
+                None => catalog.tr(lang, "settings-none"),
+            },
+            Field::Auto => on_off(settings.auto_translate),
+            Field::Format => catalog.tr(
+                lang,
+                &format!("settings-format-{}", settings.output_format.code()),
+            ),
+            Field::Original => on_off(settings.show_original),
+            Field::Romanize => on_off(settings.romanize),
+            Field::History => match settings.history_days {
+                0 => catalog.tr(lang, "settings-off"),
+                days => catalog.tr_args(
+                    lang,
+                    "settings-days",
+                    &[("days", &days.to_string())],
+                ),
+            },
+            Field::UiLanguage => match settings.ui_language {
+                Some(ui_lang) => {
+                    format!("{} {}", ui_lang.emoji(), ui_lang.code())
+                }
+                None => catalog.tr(lang, "settings-ui-language-auto"),
+            },
+            Field::Done => String::new(),
+        };
+        let text = catalog.tr_args(
+            lang,
+            &format!("settings-{}", field.code()),
+            &[("value", &value)],
+        );
+        vec![InlineKeyboardButton::callback(
+            text,
+            format!("{CALLBACK_PREFIX}{}", field.code()),
+        )]
+    });
+    (
+        catalog.tr(lang, "settings-header"),
+        InlineKeyboardMarkup::new(buttons),
+    )
+}
+
+/// Changes the setting whose button was tapped in the settings menu, if the
+/// user is an admin of the chat.
+pub async fn handle_settings_callback(
+    bot: Bot,
+    auth: Arc<Auth>,
+    catalog: Arc<Catalog>,
+    storage: Arc<Storage>,
+    query: CallbackQuery,
+) -> crate::Result<()> {
+    let field = query
+        .data
+        .as_deref()
+        .and_then(|data| data.strip_prefix(CALLBACK_PREFIX))
+        .and_then(Field::parse);
+    let (field, message) = match (field, &query.message) {
+        (Some(field), Some(message)) => (field, message),
+        _ => return Ok(()),
+    };
+    let chat = &message.chat;
+    let user = Some(&query.from);
+    let lang = ui_language_for(&auth, &storage, chat.id, user)?;
+
+    let is_admin = auth.is_authorized(&bot, chat, user).await
+        && auth
+            .role_in(&bot, chat, user)
+            .await
+            .is_ok_and(|role| role >= Role::Admin);
+    if !is_admin {
+        bot.answer_callback_query(query.id)
+            .text(catalog.tr(lang, "admin-only"))
+            .show_alert(true)
+            .await?;
+        return Ok(());
+    }
+
+    if field == Field::Done {
+        bot.edit_message_reply_markup(chat.id, message.id).await?;
+        bot.answer_callback_query(query.id).await?;
+        return Ok(());
+    }
+
+    let current = Settings::load(&auth, &storage, chat.id)?;
+    let mut stored = storage.chat_settings().get(chat.id)?;
+    match field {
+        Field::Target => {
+            stored.default_target =
+                next_language(current.default_target).map(|lang| lang.code());
+        }
+        Field::Auto => {
+            stored.auto_translate = Some(!current.auto_translate);
+            // Auto mode needs a language to translate into.
+            if current.default_target.is_none() {
+                stored.default_target = Some(lang.code());
+            }
+        }
+        Field::Format => {
+            let format = match current.output_format {
+                OutputFormat::Flags => OutputFormat::Plain,
+                OutputFormat::Plain => OutputFormat::Flags,
+            };
+            stored.output_format = Some(format.code().to_string());
+        }
+        Field::Original => stored.show_original = Some(!current.show_original),
+        Field::Romanize => stored.romanize = Some(!current.romanize),
+        Field::History => {
+            let days = next_history_days(current.history_days);
+            stored.history_days = Some(days);
+            // Opting out forgets what was kept so far, otherwise it
+            // expires as now chosen.
+            let forgotten = match days {
+                0 => storage.history().forget_chat(chat.id)?,
+                days => storage.history().set_retention(
+                    chat.id,
+                    Duration::from_secs(u64::from(days) * 24 * 60 * 60),
+                )?,
+            };
+            log::info!(
+                "Forgot {} history entries of chat [{}]",
+                forgotten,
+                chat.id
+            );
+        }
+        Field::UiLanguage => {
+            stored.ui_language =
+                next_language(current.ui_language).map(|lang| lang.code());
+        }
+        Field::Done => {}
+    }
+    storage.chat_settings().put(chat.id, &stored)?;
+    log::info!(
+        "Setting {} of chat [{}] changed by [{}]",
+        field.code(),
+        chat.id,
+        query.from.id
+    );
+
+    // The menu switches to the language just chosen.
+    let lang = ui_language_for(&auth, &storage, chat.id, user)?;
+    let settings = Settings::load(&auth, &storage, chat.id)?;
+    let (text, keyboard) = settings_menu(&catalog, lang, &settings);
+    match bot
+        .edit_message_text(chat.id, message.id, text)
+        .reply_markup(keyboard)
+        .await
+    {
+        Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified)) => {}
+        Err(err) => return Err(err.into()),
+    }
+    bot.answer_callback_query(query.id).await?;
+    Ok(())
+}
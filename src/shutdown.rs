@@ -0,0 +1,25 @@
+/// Waits for SIGINT (ctrl-c) or, on platforms that have it, SIGTERM, as
+/// sent by `docker stop` and orchestrators.
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    let mut terminate = tokio::signal::unix::signal(
+        tokio::signal::unix::SignalKind::terminate(),
+    )
+    .expect("Cannot listen for SIGTERM");
+
+    #[cfg(unix)]
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            result.expect("Cannot listen for ctrl-c");
+            log::info!("Received SIGINT, shutting down");
+        }
+        _ = terminate.recv() => log::info!("Received SIGTERM, shutting down"),
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Cannot listen for ctrl-c");
+        log::info!("Received ctrl-c, shutting down");
+    }
+}
@@ -0,0 +1,60 @@
+use rusqlite::params;
+use teloxide::types::{ChatId, UserId};
+
+use super::{now, Storage};
+
+/// A chat allowed or denied at runtime, overriding the config.
+#[derive(Debug, Clone)]
+pub struct ChatAccess {
+    pub chat_id: i64,
+    pub name: String,
+    pub allowed: bool,
+}
+
+pub struct AllowedChatsRepo<'a> {
+    storage: &'a Storage,
+}
+
+impl<'a> AllowedChatsRepo<'a> {
+    pub(super) fn new(storage: &'a Storage) -> Self {
+        Self { storage }
+    }
+
+    pub fn list(&self) -> crate::Result<Vec<ChatAccess>> {
+        let conn = self.storage.conn();
+        let mut stmt = conn.prepare(
+            "SELECT chat_id, name, allowed FROM chat_access ORDER BY chat_id",
+        )?;
+        let chats = stmt
+            .query_map([], |row| {
+                Ok(ChatAccess {
+                    chat_id: row.get(0)?,
+                    name: row.get(1)?,
+                    allowed: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(chats)
+    }
+
+    pub fn set(
+        &self,
+        chat_id: ChatId,
+        name: &str,
+        allowed: bool,
+        changed_by: UserId,
+    ) -> crate::Result<()> {
+        self.storage.conn().execute(
+            "INSERT INTO chat_access
+                (chat_id, name, allowed, changed_by, changed_at)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT (chat_id) DO UPDATE SET
+                name = excluded.name,
+                allowed = excluded.allowed,
+                changed_by = excluded.changed_by,
+                changed_at = excluded.changed_at",
+            params![chat_id.0, name, allowed, changed_by.0, now()],
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+use rusqlite::{params, OptionalExtension};
+use teloxide::types::ChatId;
+
+use super::Storage;
+
+/// Settings changed for a chat at runtime. Unset values fall back to the
+/// chat's entry in the config.
+#[derive(Debug, Clone, Default)]
+pub struct ChatSettings {
+    pub ui_language: Option<String>,
+    pub romanize: Option<bool>,
+    /// Language code translated into when none is given, e.g. `de`.
+    pub default_target: Option<String>,
+    /// Translate every message into the default target.
+    pub auto_translate: Option<bool>,
+    /// `flags` or `plain`.
+    pub output_format: Option<String>,
+    /// Repeat the original text in translations.
+    pub show_original: Option<bool>,
+    /// Days translations are kept in the history for, 0 to keep none.
+    pub history_days: Option<u32>,
+}
+
+pub struct ChatSettingsRepo<'a> {
+    storage: &'a Storage,
+}
+
+impl<'a> ChatSettingsRepo<'a> {
+    pub(super) fn new(storage: &'a Storage) -> Self {
+        Self { storage }
+    }
+
+    pub fn get(&self, chat_id: ChatId) -> crate::Result<ChatSettings> {
+        let settings = self
+            .storage
+            .conn()
+            .query_row(
+                "SELECT ui_language, romanize, default_target,
+                    auto_translate, output_format, show_original,
+                    history_days
+                FROM chat_settings
+                WHERE chat_id = ?1",
+                params![chat_id.0],
+                |row| {
+                    Ok(ChatSettings {
+                        ui_language: row.get(0)?,
+                        romanize: row.get(1)?,
+                        default_target: row.get(2)?,
+                        auto_translate: row.get(3)?,
+                        output_format: row.get(4)?,
+                        show_original: row.get(5)?,
+                        history_days: row.get(6)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(settings.unwrap_or_default())
+    }
+
+    pub fn put(
+        &self,
+        chat_id: ChatId,
+        settings: &ChatSettings,
+    ) -> crate::Result<()> {
+        self.storage.conn().execute(
+            "INSERT INTO chat_settings (
+                chat_id, ui_language, romanize, default_target,
+                auto_translate, output_format, show_original, history_days
+            )
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+            ON CONFLICT (chat_id) DO UPDATE SET
+                ui_language = excluded.ui_language,
+                romanize = excluded.romanize,
+                default_target = excluded.default_target,
+                auto_translate = excluded.auto_translate,
+                output_format = excluded.output_format,
+                show_original = excluded.show_original,
+                history_days = excluded.history_days",
+            params![
+                chat_id.0,
+                settings.ui_language,
+                settings.romanize,
+                settings.default_target,
+                settings.auto_translate,
+                settings.output_format,
+                settings.show_original,
+                settings.history_days
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_for_unknown_chats() {
+        let storage = Storage::in_memory().unwrap();
+        let settings = storage.chat_settings().get(ChatId(-1)).unwrap();
+        assert_eq!(settings.ui_language, None);
+        assert_eq!(settings.auto_translate, None);
+        assert_eq!(settings.history_days, None);
+    }
+
+    #[test]
+    fn round_trips_settings() {
+        let storage = Storage::in_memory().unwrap();
+        let settings = ChatSettings {
+            ui_language: Some("de".to_string()),
+            romanize: Some(true),
+            default_target: Some("en".to_string()),
+            auto_translate: Some(false),
+            output_format: Some("plain".to_string()),
+            show_original: Some(true),
+            history_days: Some(30),
+        };
+        storage.chat_settings().put(ChatId(-1), &settings).unwrap();
+
+        let stored = storage.chat_settings().get(ChatId(-1)).unwrap();
+        assert_eq!(stored.ui_language.as_deref(), Some("de"));
+        assert_eq!(stored.romanize, Some(true));
+        assert_eq!(stored.default_target.as_deref(), Some("en"));
+        assert_eq!(stored.auto_translate, Some(false));
+        assert_eq!(stored.output_format.as_deref(), Some("plain"));
+        assert_eq!(stored.show_original, Some(true));
+        assert_eq!(stored.history_days, Some(30));
+        let other = storage.chat_settings().get(ChatId(-2)).unwrap();
+        assert_eq!(other.ui_language, None);
+    }
+
+    #[test]
+    fn overwrites_settings() {
+        let storage = Storage::in_memory().unwrap();
+        let mut settings = ChatSettings {
+            ui_language: Some("de".to_string()),
+            ..ChatSettings::default()
+        };
+        storage.chat_settings().put(ChatId(-1), &settings).unwrap();
+        settings.ui_language = None;
+        settings.romanize = Some(false);
+        storage.chat_settings().put(ChatId(-1), &settings).unwrap();
+
+        let stored = storage.chat_settings().get(ChatId(-1)).unwrap();
+        assert_eq!(stored.ui_language, None);
+        assert_eq!(stored.romanize, Some(false));
+    }
+}
@@ -0,0 +1,199 @@
+use rusqlite::params;
+use std::time::Duration;
+use teloxide::types::{ChatId, UserId};
+
+use super::{now, Storage};
+
+/// Maximum number of entries returned by a search.
+const SEARCH_LIMIT: usize = 10;
+
+/// A translation kept in the history of a chat.
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub chat_id: i64,
+    pub user_id: u64,
+    pub source_text: String,
+    pub source_language: Option<String>,
+    pub target: String,
+    pub result: String,
+    pub created_at: i64,
+}
+
+/// Translations of the chats that opted in to keeping them. Entries expire
+/// after the `ttl` given when inserting them.
+pub struct HistoryRepo<'a> {
+    storage: &'a Storage,
+}
+
+impl<'a> HistoryRepo<'a> {
+    pub(super) fn new(storage: &'a Storage) -> Self {
+        Self { storage }
+    }
+
+    pub fn insert(
+        &self,
+        entry: &HistoryEntry,
+        ttl: Duration,
+    ) -> crate::Result<()> {
+        let conn = self.storage.conn();
+        conn.execute(
+            "DELETE FROM history WHERE expires_at <= ?1",
+            params![now()],
+        )?;
+        conn.execute(
+            "INSERT INTO history (
+                chat_id, user_id, source_text, source_language, target,
+                result, created_at, expires_at
+            )
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                entry.chat_id,
+                entry.user_id,
+                entry.source_text,
+                entry.source_language,
+                entry.target,
+                entry.result,
+                entry.created_at,
+                entry.created_at + ttl.as_secs() as i64
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The most recent entries of the chat, newest first, containing `query`
+    /// in the original text or the translation if given.
+    pub fn search(
+        &self,
+        chat_id: ChatId,
+        query: Option<&str>,
+    ) -> crate::Result<Vec<HistoryEntry>> {
+        let pattern = query.map(|query| {
+            let escaped = query
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            format!("%{escaped}%")
+        });
+        let conn = self.storage.conn();
+        let mut stmt = conn.prepare(
+            "SELECT chat_id, user_id, source_text, source_language, target,
+                result, created_at
+            FROM history
+            WHERE chat_id = ?1 AND expires_at > ?2 AND (
+                ?3 IS NULL
+                OR source_text LIKE ?3 ESCAPE '\\'
+                OR result LIKE ?3 ESCAPE '\\'
+            )
+            ORDER BY created_at DESC, id DESC
+            LIMIT ?4",
+        )?;
+        let entries = stmt
+            .query_map(
+                params![chat_id.0, now(), pattern, SEARCH_LIMIT],
+                |row| {
+                    Ok(HistoryEntry {
+                        chat_id: row.get(0)?,
+                        user_id: row.get(1)?,
+                        source_text: row.get(2)?,
+                        source_language: row.get(3)?,
+                        target: row.get(4)?,
+                        result: row.get(5)?,
+                        created_at: row.get(6)?,
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(entries)
+    }
+
+    /// Deletes all the entries of the user, in every chat. Returns how many
+    /// there were.
+    pub fn forget_user(&self, user_id: UserId) -> crate::Result<usize> {
+        let deleted = self.storage.conn().execute(
+            "DELETE FROM history WHERE user_id = ?1",
+            params![user_id.0],
+        )?;
+        Ok(deleted)
+    }
+
+    /// Makes the entries of the chat expire `ttl` after they were created,
+    /// when the chat changes how long it keeps them, and deletes the ones
+    /// that are now too old. Returns how many were deleted.
+    pub fn set_retention(
+        &self,
+        chat_id: ChatId,
+        ttl: Duration,
+    ) -> crate::Result<usize> {
+        let conn = self.storage.conn();
+        conn.execute(
+            "UPDATE history SET expires_at = created_at + ?2
+            WHERE chat_id = ?1",
+            params![chat_id.0, ttl.as_secs() as i64],
+        )?;
+        let deleted = conn.execute(
+            "DELETE FROM history WHERE chat_id = ?1 AND expires_at <= ?2",
+            params![chat_id.0, now()],
+        )?;
+        Ok(deleted)
+    }
+
+    /// Deletes all the entries of the chat.
+    pub fn forget_chat(&self, chat_id: ChatId) -> crate::Result<usize> {
+        let deleted = self.storage.conn().execute(
+            "DELETE FROM history WHERE chat_id = ?1",
+            params![chat_id.0],
+        )?;
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    fn entry(chat_id: i64, created_at: i64) -> HistoryEntry {
+        HistoryEntry {
+            chat_id,
+            user_id: 1,
+            source_text: "Hallo".to_string(),
+            source_language: Some("de".to_string()),
+            target: "en".to_string(),
+            result: "Hello".to_string(),
+            created_at,
+        }
+    }
+
+    #[test]
+    fn applies_a_new_retention_to_the_kept_entries() {
+        let storage = Storage::in_memory().unwrap();
+        let history = storage.history();
+        let ttl = |days: u64| Duration::from_secs(days * DAY as u64);
+        history
+            .insert(&entry(-1, now() - 10 * DAY), ttl(30))
+            .unwrap();
+        history.insert(&entry(-1, now() - DAY), ttl(30)).unwrap();
+        history
+            .insert(&entry(-2, now() - 10 * DAY), ttl(30))
+            .unwrap();
+
+        assert_eq!(history.set_retention(ChatId(-1), ttl(7)).unwrap(), 1);
+        assert_eq!(history.search(ChatId(-1), None).unwrap().len(), 1);
+        // Other chats keep theirs.
+        assert_eq!(history.search(ChatId(-2), None).unwrap().len(), 1);
+
+        // A longer retention keeps the remaining entries for longer.
+        assert_eq!(history.set_retention(ChatId(-1), ttl(90)).unwrap(), 0);
+        let expires_at: i64 = storage
+            .conn()
+            .query_row(
+                "SELECT expires_at - created_at FROM history
+                WHERE chat_id = -1",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(expires_at, 90 * DAY);
+    }
+}
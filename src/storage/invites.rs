@@ -0,0 +1,144 @@
+use rusqlite::params;
+use std::time::Duration;
+use teloxide::types::{ChatId, UserId};
+
+use super::{now, Storage};
+use crate::AppError;
+
+/// Single-use codes letting a new chat add itself to the allow-list. Used
+/// codes are kept as a record of who activated which chat.
+pub struct InvitesRepo<'a> {
+    storage: &'a Storage,
+}
+
+impl<'a> InvitesRepo<'a> {
+    pub(super) fn new(storage: &'a Storage) -> Self {
+        Self { storage }
+    }
+
+    pub fn create(
+        &self,
+        code: &str,
+        created_by: UserId,
+        ttl: Duration,
+    ) -> crate::Result<()> {
+        let now = now();
+        let expires_at = i64::try_from(ttl.as_secs())
+            .ok()
+            .and_then(|ttl| now.checked_add(ttl))
+            .ok_or_else(|| AppError {
+                msg: format!("Invite ttl of {}s is too long", ttl.as_secs()),
+            })?;
+        self.storage.conn().execute(
+            "INSERT INTO invites (code, created_by, created_at, expires_at)
+            VALUES (?1, ?2, ?3, ?4)",
+            params![code, created_by.0, now, expires_at],
+        )?;
+        Ok(())
+    }
+
+    /// Marks the code as used for the chat. Returns `false` if the code
+    /// doesn't exist, has expired or was used before.
+    pub fn redeem(
+        &self,
+        code: &str,
+        chat_id: ChatId,
+        chat_name: &str,
+        used_by: UserId,
+    ) -> crate::Result<bool> {
+        let now = now();
+        let updated = self.storage.conn().execute(
+            "UPDATE invites
+            SET used_by = ?2, used_in_chat = ?3, chat_name = ?4, used_at = ?5
+            WHERE code = ?1 AND used_at IS NULL AND expires_at > ?5",
+            params![code, used_by.0, chat_id.0, chat_name, now],
+        )?;
+        Ok(updated == 1)
+    }
+
+    /// Makes a redeemed code usable again, when its chat couldn't be
+    /// allowed after all.
+    pub fn restore(&self, code: &str) -> crate::Result<()> {
+        self.storage.conn().execute(
+            "UPDATE invites
+            SET used_by = NULL, used_in_chat = NULL, chat_name = NULL,
+                used_at = NULL
+            WHERE code = ?1",
+            params![code],
+        )?;
+        Ok(())
+    }
+
+    /// Whether any code can still be used.
+    pub fn has_pending(&self) -> crate::Result<bool> {
+        let pending = self.storage.conn().query_row(
+            "SELECT EXISTS (
+                SELECT 1 FROM invites
+                WHERE used_at IS NULL AND expires_at > ?1
+            )",
+            params![now()],
+            |row| row.get(0),
+        )?;
+        Ok(pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60 * 60);
+
+    #[test]
+    fn redeems_codes_once() {
+        let storage = Storage::in_memory().unwrap();
+        let invites = storage.invites();
+        invites.create("CODE", UserId(1), TTL).unwrap();
+        assert!(invites.has_pending().unwrap());
+
+        assert!(invites
+            .redeem("CODE", ChatId(-1), "team", UserId(2))
+            .unwrap());
+        assert!(!invites
+            .redeem("CODE", ChatId(-2), "other", UserId(2))
+            .unwrap());
+        assert!(!invites.has_pending().unwrap());
+        assert!(!invites
+            .redeem("OTHER", ChatId(-1), "team", UserId(2))
+            .unwrap());
+    }
+
+    #[test]
+    fn restores_redeemed_codes() {
+        let storage = Storage::in_memory().unwrap();
+        let invites = storage.invites();
+        invites.create("CODE", UserId(1), TTL).unwrap();
+        assert!(invites
+            .redeem("CODE", ChatId(-1), "team", UserId(2))
+            .unwrap());
+        invites.restore("CODE").unwrap();
+
+        assert!(invites.has_pending().unwrap());
+        assert!(invites
+            .redeem("CODE", ChatId(-1), "team", UserId(2))
+            .unwrap());
+    }
+
+    #[test]
+    fn expires_codes() {
+        let storage = Storage::in_memory().unwrap();
+        let invites = storage.invites();
+        invites.create("CODE", UserId(1), Duration::ZERO).unwrap();
+        assert!(!invites.has_pending().unwrap());
+        assert!(!invites
+            .redeem("CODE", ChatId(-1), "team", UserId(2))
+            .unwrap());
+    }
+
+    #[test]
+    fn rejects_overflowing_ttls() {
+        let storage = Storage::in_memory().unwrap();
+        let ttl = Duration::from_secs(u64::MAX);
+        assert!(storage.invites().create("CODE", UserId(1), ttl).is_err());
+    }
+}
@@ -0,0 +1,226 @@
+use rusqlite::params;
+use std::time::Duration;
+use teloxide::types::{ChatId, MessageId};
+
+use super::{now, Storage};
+use crate::limits::Purpose;
+
+/// What has to be translated again when a tracked message is edited.
+#[derive(Debug, Clone)]
+pub enum Source {
+    /// The `/translate` command message itself, which is parsed again.
+    Command,
+    /// A message that was replied to with `/translate <target>`, or
+    /// translated by auto mode, which the translation is reserved for.
+    Original { target: String, purpose: Purpose },
+}
+
+#[derive(Debug, Clone)]
+pub struct TrackedMessage {
+    pub chat_id: i64,
+    pub message_id: i32,
+    pub reply_id: i32,
+    pub source: Source,
+}
+
+/// Maps translated messages to the bot replies holding their translation.
+/// Entries expire after the `ttl` given when inserting them.
+pub struct MessageMapRepo<'a> {
+    storage: &'a Storage,
+}
+
+impl<'a> MessageMapRepo<'a> {
+    pub(super) fn new(storage: &'a Storage) -> Self {
+        Self { storage }
+    }
+
+    pub fn insert(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        reply_id: MessageId,
+        source: Source,
+        ttl: Duration,
+    ) -> crate::Result<()> {
+        let (target, purpose) = match source {
+            Source::Command => (None, None),
+            Source::Original { target, purpose } => {
+                (Some(target), Some(purpose.code()))
+            }
+        };
+        let conn = self.storage.conn();
+        conn.execute(
+            "DELETE FROM message_map WHERE expires_at <= ?1",
+            params![now()],
+        )?;
+        conn.execute(
+            "INSERT INTO message_map
+                (chat_id, message_id, reply_id, target, purpose, expires_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                chat_id.0,
+                message_id.0,
+                reply_id.0,
+                target,
+                purpose,
+                now() + ttl.as_secs() as i64
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn get(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> crate::Result<Vec<TrackedMessage>> {
+        let conn = self.storage.conn();
+        let mut stmt = conn.prepare(
+            "SELECT chat_id, message_id, reply_id, target, purpose
+            FROM message_map
+            WHERE chat_id = ?1 AND message_id = ?2 AND expires_at > ?3",
+        )?;
+        let tracked = stmt
+            .query_map(params![chat_id.0, message_id.0, now()], |row| {
+                Ok(TrackedMessage {
+                    chat_id: row.get(0)?,
+                    message_id: row.get(1)?,
+                    reply_id: row.get(2)?,
+                    source: match row.get::<_, Option<String>>(3)? {
+                        Some(target) => Source::Original {
+                            target,
+                            purpose: row
+                                .get::<_, Option<String>>(4)?
+                                .as_deref()
+                                .and_then(Purpose::parse)
+                                .unwrap_or(Purpose::Command),
+                        },
+                        None => Source::Command,
+                    },
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(tracked)
+    }
+
+    /// Whether `reply_id` is a translation the bot has sent in the chat.
+    pub fn is_reply(
+        &self,
+        chat_id: ChatId,
+        reply_id: MessageId,
+    ) -> crate::Result<bool> {
+        let exists = self.storage.conn().query_row(
+            "SELECT EXISTS (SELECT 1 FROM message_map
+                WHERE chat_id = ?1 AND reply_id = ?2 AND expires_at > ?3)",
+            params![chat_id.0, reply_id.0, now()],
+            |row| row.get(0),
+        )?;
+        Ok(exists)
+    }
+
+    /// Stops tracking the messages translated by the reply `reply_id`.
+    pub fn remove_reply(
+        &self,
+        chat_id: ChatId,
+        reply_id: MessageId,
+    ) -> crate::Result<()> {
+        self.storage.conn().execute(
+            "DELETE FROM message_map WHERE chat_id = ?1 AND reply_id = ?2",
+            params![chat_id.0, reply_id.0],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60 * 60);
+
+    #[test]
+    fn tracks_messages_and_their_replies() {
+        let storage = Storage::in_memory().unwrap();
+        let messages = storage.messages();
+        let chat_id = ChatId(-1);
+        messages
+            .insert(chat_id, MessageId(1), MessageId(2), Source::Command, TTL)
+            .unwrap();
+        let original = Source::Original {
+            target: "en+rom".to_string(),
+            purpose: Purpose::Command,
+        };
+        messages
+            .insert(chat_id, MessageId(3), MessageId(2), original, TTL)
+            .unwrap();
+        let auto = Source::Original {
+            target: "de".to_string(),
+            purpose: Purpose::Auto,
+        };
+        messages
+            .insert(chat_id, MessageId(4), MessageId(5), auto, TTL)
+            .unwrap();
+
+        let tracked = messages.get(chat_id, MessageId(1)).unwrap();
+        assert_eq!(tracked.len(), 1);
+        assert_eq!(tracked[0].reply_id, 2);
+        assert!(matches!(tracked[0].source, Source::Command));
+        let tracked = messages.get(chat_id, MessageId(3)).unwrap();
+        assert!(matches!(
+            &tracked[0].source,
+            Source::Original { target, purpose: Purpose::Command }
+                if target == "en+rom"
+        ));
+        let tracked = messages.get(chat_id, MessageId(4)).unwrap();
+        assert!(matches!(
+            &tracked[0].source,
+            Source::Original { target, purpose: Purpose::Auto } if target == "de"
+        ));
+        assert!(messages.get(ChatId(-2), MessageId(1)).unwrap().is_empty());
+
+        assert!(messages.is_reply(chat_id, MessageId(2)).unwrap());
+        assert!(!messages.is_reply(chat_id, MessageId(1)).unwrap());
+    }
+
+    #[test]
+    fn removes_replies() {
+        let storage = Storage::in_memory().unwrap();
+        let messages = storage.messages();
+        let chat_id = ChatId(-1);
+        messages
+            .insert(chat_id, MessageId(1), MessageId(2), Source::Command, TTL)
+            .unwrap();
+        messages.remove_reply(chat_id, MessageId(2)).unwrap();
+
+        assert!(!messages.is_reply(chat_id, MessageId(2)).unwrap());
+        assert!(messages.get(chat_id, MessageId(1)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn expires_entries() {
+        let storage = Storage::in_memory().unwrap();
+        let messages = storage.messages();
+        let chat_id = ChatId(-1);
+        messages
+            .insert(
+                chat_id,
+                MessageId(1),
+                MessageId(2),
+                Source::Command,
+                Duration::ZERO,
+            )
+            .unwrap();
+        assert!(messages.get(chat_id, MessageId(1)).unwrap().is_empty());
+        assert!(!messages.is_reply(chat_id, MessageId(2)).unwrap());
+
+        // Expired entries are deleted on the next insert.
+        messages
+            .insert(chat_id, MessageId(3), MessageId(4), Source::Command, TTL)
+            .unwrap();
+        let count: i64 = storage
+            .conn()
+            .query_row("SELECT COUNT(*) FROM message_map", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+}
@@ -0,0 +1,244 @@
+use rusqlite::{Connection, TransactionBehavior};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod allowed_chats;
+mod chat_settings;
+mod history;
+mod invites;
+mod messages;
+mod usage;
+mod user_preferences;
+
+pub use allowed_chats::AllowedChatsRepo;
+pub use chat_settings::{ChatSettings, ChatSettingsRepo};
+pub use history::{HistoryEntry, HistoryRepo};
+pub use invites::InvitesRepo;
+pub use messages::{MessageMapRepo, Source};
+pub use usage::{this_month, QuotaPeriod, UsageRepo};
+pub use user_preferences::{UserPreferences, UserPreferencesRepo};
+
+/// Path of the database that is kept in memory instead of on disk.
+pub const IN_MEMORY: &str = ":memory:";
+
+/// Schema migrations, applied in order. The index of the last applied
+/// migration + 1 is kept in the database's `user_version`, so migrations
+/// must never be edited once released, only appended.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE chat_settings (
+        chat_id INTEGER PRIMARY KEY,
+        ui_language TEXT,
+        romanize INTEGER
+    );
+
+    CREATE TABLE user_preferences (
+        user_id INTEGER PRIMARY KEY,
+        ui_language TEXT
+    );
+
+    CREATE TABLE message_map (
+        chat_id INTEGER NOT NULL,
+        message_id INTEGER NOT NULL,
+        reply_id INTEGER NOT NULL,
+        target TEXT,
+        expires_at INTEGER NOT NULL
+    );
+    CREATE INDEX message_map_message ON message_map (chat_id, message_id);
+    CREATE INDEX message_map_reply ON message_map (chat_id, reply_id);
+",
+    "
+    CREATE TABLE chat_access (
+        chat_id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        allowed INTEGER NOT NULL,
+        changed_by INTEGER NOT NULL,
+        changed_at INTEGER NOT NULL
+    );
+",
+    "
+    CREATE TABLE invites (
+        code TEXT PRIMARY KEY,
+        created_by INTEGER NOT NULL,
+        created_at INTEGER NOT NULL,
+        expires_at INTEGER NOT NULL,
+        used_by INTEGER,
+        used_in_chat INTEGER,
+        chat_name TEXT,
+        used_at INTEGER
+    );
+",
+    "
+    CREATE TABLE char_usage (
+        day TEXT NOT NULL,
+        provider TEXT NOT NULL,
+        chat_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        chars INTEGER NOT NULL,
+        PRIMARY KEY (day, provider, chat_id, user_id)
+    );
+",
+    "
+    ALTER TABLE chat_settings ADD COLUMN default_target TEXT;
+    ALTER TABLE chat_settings ADD COLUMN auto_translate INTEGER;
+    ALTER TABLE chat_settings ADD COLUMN output_format TEXT;
+    ALTER TABLE chat_settings ADD COLUMN show_original INTEGER;
+",
+    "
+    ALTER TABLE chat_settings ADD COLUMN history_days INTEGER;
+
+    CREATE TABLE history (
+        id INTEGER PRIMARY KEY,
+        chat_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        source_text TEXT NOT NULL,
+        source_language TEXT,
+        target TEXT NOT NULL,
+        result TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        expires_at INTEGER NOT NULL
+    );
+    CREATE INDEX history_chat ON history (chat_id, created_at);
+    CREATE INDEX history_user ON history (user_id);
+",
+    "
+    ALTER TABLE message_map ADD COLUMN purpose TEXT;
+",
+];
+
+/// Embedded SQLite database holding everything the bot learns at runtime.
+///
+/// Queries are short and run on the calling task while holding the
+/// connection lock. With that single connection, SQLite's default rollback
+/// journal is kept rather than WAL, which would only help concurrent
+/// readers, so every commit is in the database file right away.
+#[derive(Clone)]
+pub struct Storage {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl Storage {
+    /// Opens (or creates) the database at `path` and migrates it to the
+    /// latest schema. `:memory:` keeps the database in memory.
+    pub fn open(path: &str) -> crate::Result<Self> {
+        let conn = match path {
+            IN_MEMORY => Connection::open_in_memory()?,
+            path => Connection::open(path)?,
+        };
+        let storage = Self {
+            conn: Arc::new(Mutex::new(conn)),
+        };
+        storage.migrate()?;
+        log::info!("Opened database {}", path);
+        Ok(storage)
+    }
+
+    pub fn in_memory() -> crate::Result<Self> {
+        Self::open(IN_MEMORY)
+    }
+
+    fn migrate(&self) -> crate::Result<()> {
+        let mut conn = self.conn();
+        let version: usize =
+            conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            log::info!("Applying database migration {}", i + 1);
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", i + 1)?;
+            tx.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Lets SQLite update its query planner statistics before the process
+    /// exits. The data itself is written on every commit.
+    pub fn optimize(&self) -> crate::Result<()> {
+        self.conn().execute_batch("PRAGMA optimize")?;
+        Ok(())
+    }
+
+    /// Fails unless the database can be written to, without changing it.
+    pub fn check_writable(&self) -> crate::Result<()> {
+        let mut conn = self.conn();
+        let tx =
+            conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let version: usize =
+            tx.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        tx.pragma_update(None, "user_version", version)?;
+        tx.rollback()?;
+        Ok(())
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().expect("database connection poisoned")
+    }
+
+    pub fn chat_settings(&self) -> ChatSettingsRepo<'_> {
+        ChatSettingsRepo::new(self)
+    }
+
+    pub fn user_preferences(&self) -> UserPreferencesRepo<'_> {
+        UserPreferencesRepo::new(self)
+    }
+
+    pub fn allowed_chats(&self) -> AllowedChatsRepo<'_> {
+        AllowedChatsRepo::new(self)
+    }
+
+    pub fn history(&self) -> HistoryRepo<'_> {
+        HistoryRepo::new(self)
+    }
+
+    pub fn invites(&self) -> InvitesRepo<'_> {
+        InvitesRepo::new(self)
+    }
+
+    pub fn usage(&self) -> UsageRepo<'_> {
+        UsageRepo::new(self)
+    }
+
+    pub fn messages(&self) -> MessageMapRepo<'_> {
+        MessageMapRepo::new(self)
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_version(storage: &Storage) -> usize {
+        storage
+            .conn()
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn applies_all_migrations_to_a_new_database() {
+        let storage = Storage::in_memory().unwrap();
+        assert_eq!(user_version(&storage), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn migrates_only_once() {
+        let storage = Storage::in_memory().unwrap();
+        storage.migrate().unwrap();
+        assert_eq!(user_version(&storage), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn is_writable() {
+        let storage = Storage::in_memory().unwrap();
+        storage.check_writable().unwrap();
+        assert_eq!(user_version(&storage), MIGRATIONS.len());
+    }
+}
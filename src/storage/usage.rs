@@ -0,0 +1,249 @@
+use chrono::Utc;
+use rusqlite::{params, Connection};
+use teloxide::types::{ChatId, UserId};
+
+use super::Storage;
+
+/// A character quota of a chat.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaPeriod {
+    Day,
+    Month,
+}
+
+/// Characters sent to a translation provider in a chat during a month.
+#[derive(Debug, Clone)]
+pub struct ProviderChatUsage {
+    pub provider: String,
+    pub chat_id: i64,
+    pub chars: u64,
+}
+
+/// Characters sent to the translation providers, summed up per UTC day,
+/// provider, chat and user. Messages without a sender are counted for
+/// user 0.
+pub struct UsageRepo<'a> {
+    storage: &'a Storage,
+}
+
+fn today() -> String {
+    Utc::now().format("%Y-%m-%d").to_string()
+}
+
+fn first_of_month() -> String {
+    Utc::now().format("%Y-%m-01").to_string()
+}
+
+/// The current UTC month, e.g. `2022-11`.
+pub fn this_month() -> String {
+    Utc::now().format("%Y-%m").to_string()
+}
+
+impl<'a> UsageRepo<'a> {
+    pub(super) fn new(storage: &'a Storage) -> Self {
+        Self { storage }
+    }
+
+    /// Records `chars` characters unless the chat would go over its `daily`
+    /// or `monthly` quota, and returns the quota that would be exceeded if
+    /// so. Checking and recording in one transaction keeps translations
+    /// running at the same time from overshooting the quota together.
+    pub fn reserve(
+        &self,
+        provider: &str,
+        chat_id: ChatId,
+        user_id: Option<UserId>,
+        chars: u64,
+        quotas: (Option<u64>, Option<u64>),
+    ) -> crate::Result<Option<QuotaPeriod>> {
+        let mut conn = self.storage.conn();
+        let tx = conn.transaction()?;
+        let (daily, monthly) = quotas;
+        for (period, quota, since) in [
+            (QuotaPeriod::Day, daily, today()),
+            (QuotaPeriod::Month, monthly, first_of_month()),
+        ] {
+            if let Some(quota) = quota {
+                if chat_chars_since(&tx, chat_id, &since)? + chars > quota {
+                    return Ok(Some(period));
+                }
+            }
+        }
+        record(&tx, provider, chat_id, user_id, chars)?;
+        tx.commit()?;
+        Ok(None)
+    }
+
+    /// Gives back characters reserved today that weren't translated after
+    /// all.
+    pub fn release(
+        &self,
+        provider: &str,
+        chat_id: ChatId,
+        user_id: Option<UserId>,
+        chars: u64,
+    ) -> crate::Result<()> {
+        self.storage.conn().execute(
+            "UPDATE char_usage SET chars = MAX(chars - ?5, 0)
+            WHERE day = ?1 AND provider = ?2 AND chat_id = ?3
+                AND user_id = ?4",
+            params![
+                today(),
+                provider,
+                chat_id.0,
+                user_id.map_or(0, |id| id.0),
+                chars
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn chat_chars_today(&self, chat_id: ChatId) -> crate::Result<u64> {
+        chat_chars_since(&self.storage.conn(), chat_id, &today())
+    }
+
+    pub fn chat_chars_this_month(&self, chat_id: ChatId) -> crate::Result<u64> {
+        chat_chars_since(&self.storage.conn(), chat_id, &first_of_month())
+    }
+
+    /// The usage per provider and chat in `month`, e.g. `2022-11`, busiest
+    /// first.
+    pub fn by_provider_and_chat(
+        &self,
+        month: &str,
+    ) -> crate::Result<Vec<ProviderChatUsage>> {
+        let conn = self.storage.conn();
+        let mut stmt = conn.prepare(
+            "SELECT provider, chat_id, SUM(chars) AS total FROM char_usage
+            WHERE substr(day, 1, 7) = ?1
+            GROUP BY provider, chat_id
+            ORDER BY provider, total DESC",
+        )?;
+        let usage = stmt
+            .query_map(params![month], |row| {
+                Ok(ProviderChatUsage {
+                    provider: row.get(0)?,
+                    chat_id: row.get(1)?,
+                    chars: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(usage)
+    }
+}
+
+fn record(
+    conn: &Connection,
+    provider: &str,
+    chat_id: ChatId,
+    user_id: Option<UserId>,
+    chars: u64,
+) -> crate::Result<()> {
+    conn.execute(
+        "INSERT INTO char_usage (day, provider, chat_id, user_id, chars)
+        VALUES (?1, ?2, ?3, ?4, ?5)
+        ON CONFLICT (day, provider, chat_id, user_id)
+        DO UPDATE SET chars = chars + excluded.chars",
+        params![
+            today(),
+            provider,
+            chat_id.0,
+            user_id.map_or(0, |id| id.0),
+            chars
+        ],
+    )?;
+    Ok(())
+}
+
+fn chat_chars_since(
+    conn: &Connection,
+    chat_id: ChatId,
+    day: &str,
+) -> crate::Result<u64> {
+    let chars = conn.query_row(
+        "SELECT COALESCE(SUM(chars), 0) FROM char_usage
+        WHERE chat_id = ?1 AND day >= ?2",
+        params![chat_id.0, day],
+        |row| row.get(0),
+    )?;
+    Ok(chars)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAT: ChatId = ChatId(-1);
+
+    #[test]
+    fn reserves_within_the_quotas() {
+        let storage = Storage::in_memory().unwrap();
+        let usage = storage.usage();
+        let quotas = (Some(100), Some(150));
+        assert_eq!(
+            usage
+                .reserve("google", CHAT, Some(UserId(1)), 60, quotas)
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            usage
+                .reserve("google", CHAT, Some(UserId(2)), 40, quotas)
+                .unwrap(),
+            None
+        );
+        assert_eq!(usage.chat_chars_today(CHAT).unwrap(), 100);
+
+        assert_eq!(
+            usage.reserve("google", CHAT, None, 1, quotas).unwrap(),
+            Some(QuotaPeriod::Day)
+        );
+        assert_eq!(
+            usage
+                .reserve("google", CHAT, None, 1, (None, Some(100)))
+                .unwrap(),
+            Some(QuotaPeriod::Month)
+        );
+        // Refused reservations aren't counted.
+        assert_eq!(usage.chat_chars_this_month(CHAT).unwrap(), 100);
+        assert_eq!(usage.chat_chars_today(ChatId(-2)).unwrap(), 0);
+    }
+
+    #[test]
+    fn releases_reservations() {
+        let storage = Storage::in_memory().unwrap();
+        let usage = storage.usage();
+        let quotas = (Some(100), None);
+        usage.reserve("google", CHAT, None, 100, quotas).unwrap();
+        usage.release("google", CHAT, None, 100).unwrap();
+        assert_eq!(usage.chat_chars_today(CHAT).unwrap(), 0);
+        assert_eq!(
+            usage.reserve("google", CHAT, None, 100, quotas).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn sums_usage_by_provider_and_chat() {
+        let storage = Storage::in_memory().unwrap();
+        let usage = storage.usage();
+        let unlimited = (None, None);
+        for (chat_id, user_id, chars) in [
+            (CHAT, Some(UserId(1)), 10),
+            (CHAT, Some(UserId(2)), 20),
+            (ChatId(-2), None, 50),
+        ] {
+            usage
+                .reserve("google", chat_id, user_id, chars, unlimited)
+                .unwrap();
+        }
+
+        let report = usage.by_provider_and_chat(&this_month()).unwrap();
+        let chats = report
+            .iter()
+            .map(|usage| (usage.chat_id, usage.chars))
+            .collect::<Vec<_>>();
+        assert_eq!(chats, [(-2, 50), (-1, 30)]);
+        assert!(usage.by_provider_and_chat("2000-01").unwrap().is_empty());
+    }
+}
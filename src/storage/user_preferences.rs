@@ -0,0 +1,96 @@
+use rusqlite::{params, OptionalExtension};
+use teloxide::types::UserId;
+
+use super::Storage;
+
+/// Preferences of a user, applying in every chat they use the bot in.
+/// Users set them with `/language`.
+#[derive(Debug, Clone, Default)]
+pub struct UserPreferences {
+    pub ui_language: Option<String>,
+}
+
+pub struct UserPreferencesRepo<'a> {
+    storage: &'a Storage,
+}
+
+impl<'a> UserPreferencesRepo<'a> {
+    pub(super) fn new(storage: &'a Storage) -> Self {
+        Self { storage }
+    }
+
+    pub fn get(&self, user_id: UserId) -> crate::Result<UserPreferences> {
+        let preferences = self
+            .storage
+            .conn()
+            .query_row(
+                "SELECT ui_language FROM user_preferences WHERE user_id = ?1",
+                params![user_id.0],
+                |row| {
+                    Ok(UserPreferences {
+                        ui_language: row.get(0)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(preferences.unwrap_or_default())
+    }
+
+    /// Sets the UI language of the user, or clears it with `None` to follow
+    /// their Telegram client again.
+    pub fn set_ui_language(
+        &self,
+        user_id: UserId,
+        ui_language: Option<&str>,
+    ) -> crate::Result<()> {
+        self.storage.conn().execute(
+            "INSERT INTO user_preferences (user_id, ui_language)
+            VALUES (?1, ?2)
+            ON CONFLICT (user_id) DO UPDATE SET ui_language = ?2",
+            params![user_id.0, ui_language],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_for_unknown_users() {
+        let storage = Storage::in_memory().unwrap();
+        let preferences = storage.user_preferences().get(UserId(1)).unwrap();
+        assert_eq!(preferences.ui_language, None);
+    }
+
+    #[test]
+    fn reads_stored_preferences() {
+        let storage = Storage::in_memory().unwrap();
+        storage
+            .conn()
+            .execute(
+                "INSERT INTO user_preferences (user_id, ui_language)
+                VALUES (?1, ?2)",
+                params![1, "de"],
+            )
+            .unwrap();
+        let preferences = storage.user_preferences().get(UserId(1)).unwrap();
+        assert_eq!(preferences.ui_language.as_deref(), Some("de"));
+        let other = storage.user_preferences().get(UserId(2)).unwrap();
+        assert_eq!(other.ui_language, None);
+    }
+
+    #[test]
+    fn sets_and_clears_the_ui_language() {
+        let storage = Storage::in_memory().unwrap();
+        let repo = storage.user_preferences();
+        repo.set_ui_language(UserId(1), Some("de")).unwrap();
+        repo.set_ui_language(UserId(1), Some("ko")).unwrap();
+        let preferences = repo.get(UserId(1)).unwrap();
+        assert_eq!(preferences.ui_language.as_deref(), Some("ko"));
+
+        repo.set_ui_language(UserId(1), None).unwrap();
+        assert_eq!(repo.get(UserId(1)).unwrap().ui_language, None);
+    }
+}
@@ -0,0 +1,180 @@
+use rustls_pemfile::Item;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use crate::AppError;
+
+/// Checks that the certificate and key files can be served, the way the
+/// server loads them: the certificate file has to hold a certificate and
+/// the key file has to start with a private key that rustls supports.
+pub fn check_cert_and_key(
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<(), String> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|err| format!("Cannot read {}: {err}", path.display()))
+    };
+    let certs =
+        rustls_pemfile::certs(&mut open(cert_path)?).map_err(|err| {
+            format!("Cannot parse {}: {err}", cert_path.display())
+        })?;
+    if certs.is_empty() {
+        return Err(format!("No certificate found in {}", cert_path.display()));
+    }
+    let key = match rustls_pemfile::read_one(&mut open(key_path)?) {
+        Ok(Some(
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key),
+        )) => key,
+        Ok(_) => {
+            return Err(format!(
+                "No private key found at the start of {}",
+                key_path.display()
+            ))
+        }
+        Err(err) => {
+            return Err(format!("Cannot parse {}: {err}", key_path.display()))
+        }
+    };
+    rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            certs.into_iter().map(rustls::Certificate).collect(),
+            rustls::PrivateKey(key),
+        )
+        .map_err(|err| format!("Cannot use {}: {err}", key_path.display()))?;
+    Ok(())
+}
+
+/// Whether the first certificate in the PEM file is self-signed, i.e. its
+/// issuer is its subject. Telegram only trusts those once it's given them.
+pub fn is_self_signed(cert_path: &Path) -> crate::Result<bool> {
+    let mut reader = BufReader::new(File::open(cert_path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    let (issuer, subject) = certs
+        .first()
+        .and_then(|cert| issuer_and_subject(cert))
+        .ok_or_else(|| AppError {
+            msg: format!("No certificate found in {}", cert_path.display()),
+        })?;
+    Ok(issuer == subject)
+}
+
+/// The encoded issuer and subject names of a DER X.509 certificate.
+fn issuer_and_subject(der: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (cert, _) = der_element(der)?;
+    let (tbs, _) = der_element(cert)?;
+    // The version is optional, and tagged [0] when present.
+    let fields = match tbs.first() {
+        Some(0xa0) => der_element(tbs)?.1,
+        _ => tbs,
+    };
+    let (_serial_number, rest) = der_element(fields)?;
+    let (_signature, rest) = der_element(rest)?;
+    let (issuer, rest) = der_element(rest)?;
+    let (_validity, rest) = der_element(rest)?;
+    let (subject, _) = der_element(rest)?;
+    Some((issuer, subject))
+}
+
+/// Splits the first DER element into its contents and what follows it.
+fn der_element(der: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (&first_len_byte, rest) = der.get(1..)?.split_first()?;
+    let (len, rest) = match first_len_byte {
+        len if len < 0x80 => (len as usize, rest),
+        long_form => {
+            let len_bytes = (long_form & 0x7f) as usize;
+            if len_bytes == 0 || len_bytes > 4 {
+                return None;
+            }
+            let len = rest
+                .get(..len_bytes)?
+                .iter()
+                .fold(0, |len, byte| len << 8 | *byte as usize);
+            (len, rest.get(len_bytes..)?)
+        }
+    };
+    Some((rest.get(..len)?, rest.get(len..)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/tls")
+            .join(name)
+    }
+
+    #[test]
+    fn tells_self_signed_certificates() {
+        assert!(is_self_signed(&testdata("self-signed.pem")).unwrap());
+        // Only the first certificate of a chain counts.
+        assert!(!is_self_signed(&testdata("ca-signed.pem")).unwrap());
+    }
+
+    #[test]
+    fn reads_v1_certificates_without_a_version() {
+        assert!(is_self_signed(&testdata("v1.pem")).unwrap());
+    }
+
+    #[test]
+    fn finds_no_certificate_in_a_key() {
+        assert!(is_self_signed(&testdata("self-signed.key")).is_err());
+    }
+
+    #[test]
+    fn splits_der_elements() {
+        assert_eq!(
+            der_element(&[0x02, 0x01, 0x05, 0xff]),
+            Some((&[0x05][..], &[0xff][..]))
+        );
+        // The length in the long form, in one and two bytes.
+        let mut long = vec![0x04, 0x81, 0x80];
+        long.extend([0xaa; 0x80]);
+        assert_eq!(der_element(&long), Some((&long[3..], &[][..])));
+        let mut longer = vec![0x04, 0x82, 0x01, 0x00];
+        longer.extend([0xaa; 0x100]);
+        assert_eq!(der_element(&longer), Some((&longer[4..], &[][..])));
+    }
+
+    #[test]
+    fn rejects_truncated_der_elements() {
+        assert_eq!(der_element(&[]), None);
+        assert_eq!(der_element(&[0x30]), None);
+        assert_eq!(der_element(&[0x30, 0x02, 0x01]), None);
+        assert_eq!(der_element(&[0x30, 0x82, 0x01]), None);
+        // An indefinite length, which DER doesn't allow.
+        assert_eq!(der_element(&[0x30, 0x80, 0x00, 0x00]), None);
+    }
+
+    #[test]
+    fn checks_certificates_and_keys() {
+        let cert = testdata("self-signed.pem");
+        let key = testdata("self-signed.key");
+        assert_eq!(check_cert_and_key(&cert, &key), Ok(()));
+        assert_eq!(
+            check_cert_and_key(
+                &testdata("ca-signed.pem"),
+                &testdata("ca-signed.key")
+            ),
+            Ok(())
+        );
+
+        let err = check_cert_and_key(&key, &key).unwrap_err();
+        assert!(err.starts_with("No certificate found in "), "{err}");
+        let err = check_cert_and_key(&cert, &cert).unwrap_err();
+        assert!(
+            err.starts_with("No private key found at the start of "),
+            "{err}"
+        );
+        let err =
+            check_cert_and_key(&cert, &testdata("missing.key")).unwrap_err();
+        assert!(err.starts_with("Cannot read "), "{err}");
+    }
+}
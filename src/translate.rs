@@ -1,7 +1,12 @@
 use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use crate::{config::SecretString, AppError, Result};
 
+/// How long a successful request vouches for the API being reachable.
+const RECENT_SUCCESS: Duration = Duration::from_secs(5 * 60);
+
 #[derive(Debug, Serialize)]
 struct TranslateQuery {
     q: String,
@@ -58,14 +63,52 @@ pub struct Translation {
 pub struct GoogleCloudClient {
     pub api_key: SecretString,
     pub http_client: reqwest::Client,
+    last_success: Mutex<Option<Instant>>,
 }
 
 impl GoogleCloudClient {
+    /// Name of the provider in the usage accounting and price rates.
+    pub const PROVIDER: &'static str = "google";
+
     pub fn new(api_key: SecretString) -> Self {
         Self {
             api_key,
             http_client: reqwest::Client::new(),
+            last_success: Mutex::new(None),
+        }
+    }
+
+    fn record_success(&self) {
+        *self.last_success.lock().expect("last success poisoned") =
+            Some(Instant::now());
+    }
+
+    /// Whether the API is reachable, going by the last successful request,
+    /// or by listing the supported languages, which costs nothing.
+    pub async fn check(&self) -> Result<String> {
+        let last_success =
+            *self.last_success.lock().expect("last success poisoned");
+        if let Some(ago) = last_success.map(|at| at.elapsed()) {
+            if ago < RECENT_SUCCESS {
+                return Ok(format!("Last success {}s ago", ago.as_secs()));
+            }
+        }
+
+        let res = self
+            .http_client
+            .get("https://translation.googleapis.com/language/translate/v2/languages")
+            .query(&[("key", self.api_key.expose_secret())])
+            .send()
+            .await
+            // The url has the API key in it.
+            .map_err(reqwest::Error::without_url)?;
+        if res.status() != 200 {
+            return Err(AppError {
+                msg: format!("Google Cloud Translate Error: {}", res.status()),
+            });
         }
+        self.record_success();
+        Ok("Listed the languages".to_string())
     }
 
     pub async fn translate(
@@ -108,6 +151,7 @@ impl GoogleCloudClient {
             });
         }
 
+        self.record_success();
         Ok(out.data.translations[0].clone())
     }
 }
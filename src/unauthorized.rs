@@ -0,0 +1,227 @@
+//! Turning away chats that aren't allowed to use the bot, as configured by
+//! the [`UnauthorizedPolicy`](crate::config::UnauthorizedPolicy).
+
+use std::sync::Arc;
+use teloxide::prelude::*;
+use teloxide::types::{Chat, ChatMemberUpdated, User};
+
+use crate::auth::{chat_name, ACTIVATION_GRACE};
+use crate::i18n::Catalog;
+use crate::language::Language;
+use crate::storage::Storage;
+use crate::{Auth, Config};
+
+fn user_language(user: Option<&User>) -> Language {
+    user.and_then(|user| user.language_code.as_deref())
+        .and_then(Language::parse_language_tag)
+        .unwrap_or(Language::English)
+}
+
+/// Answers a message in a chat that isn't allowed.
+pub async fn handle_unauthorized_message(
+    bot: Bot,
+    config: Arc<Config>,
+    auth: Arc<Auth>,
+    catalog: Arc<Catalog>,
+    storage: Arc<Storage>,
+    msg: Message,
+) -> crate::Result<()> {
+    reject_chat(
+        &bot,
+        &config,
+        &auth,
+        &catalog,
+        &storage,
+        &msg.chat,
+        msg.from(),
+    )
+    .await
+}
+
+/// Turns the bot away right when it's added to a group that isn't allowed.
+pub async fn handle_my_chat_member(
+    bot: Bot,
+    config: Arc<Config>,
+    auth: Arc<Auth>,
+    catalog: Arc<Catalog>,
+    storage: Arc<Storage>,
+    update: ChatMemberUpdated,
+) -> crate::Result<()> {
+    let was_added = update.new_chat_member.is_present()
+        && !update.old_chat_member.is_present();
+    if !was_added
+        || update.chat.is_private()
+        || auth.chat_is_allowed(&update.chat.id)
+    {
+        return Ok(());
+    }
+    log::warn!(
+        "Added to unauthorized chat [{}] by [{}]",
+        update.chat.id,
+        update.from.id
+    );
+    if config.unauthorized_policy.leave_groups
+        && storage.invites().has_pending()?
+    {
+        auth.record_join(update.chat.id);
+        leave_unless_activated(
+            bot.clone(),
+            config.clone(),
+            auth.clone(),
+            catalog.clone(),
+            storage.clone(),
+            update.clone(),
+        );
+    }
+    reject_chat(
+        &bot,
+        &config,
+        &auth,
+        &catalog,
+        &storage,
+        &update.chat,
+        Some(&update.from),
+    )
+    .await
+}
+
+/// Turns the bot away from the group it was added to once the grace period
+/// for `/activate` is over, unless the group was allowed meanwhile.
+fn leave_unless_activated(
+    bot: Bot,
+    config: Arc<Config>,
+    auth: Arc<Auth>,
+    catalog: Arc<Catalog>,
+    storage: Arc<Storage>,
+    update: ChatMemberUpdated,
+) {
+    tokio::spawn(async move {
+        tokio::time::sleep(ACTIVATION_GRACE).await;
+        if auth.chat_is_allowed(&update.chat.id) {
+            return;
+        }
+        let rejected = reject_chat(
+            &bot,
+            &config,
+            &auth,
+            &catalog,
+            &storage,
+            &update.chat,
+            Some(&update.from),
+        )
+        .await;
+        if let Err(err) = rejected {
+            log::warn!(
+                "Cannot turn away chat [{}] after the grace period: {err}",
+                update.chat.id
+            );
+        }
+    });
+}
+
+/// Whether the bot stays in the unauthorized group for now, as it was added
+/// to it recently and someone may send `/activate` with an invite code.
+fn awaits_activation(
+    auth: &Auth,
+    storage: &Storage,
+    chat_id: ChatId,
+) -> crate::Result<bool> {
+    Ok(auth.awaits_activation(chat_id) && storage.invites().has_pending()?)
+}
+
+async fn reject_chat(
+    bot: &Bot,
+    config: &Config,
+    auth: &Auth,
+    catalog: &Catalog,
+    storage: &Storage,
+    chat: &Chat,
+    user: Option<&User>,
+) -> crate::Result<()> {
+    let policy = config.unauthorized_policy;
+    // Owners adding the bot allow the chat themselves, and groups the bot
+    // was just added to may have an invite code for `/activate`.
+    let leave = policy.leave_groups
+        && !chat.is_private()
+        && !user.is_some_and(|user| auth.is_owner(user.id))
+        && !awaits_activation(auth, storage, chat.id)?;
+
+    if !auth.record_rejection(chat.id) && !leave {
+        return Ok(());
+    }
+
+    if policy.reply {
+        let lang = user_language(user);
+        bot.send_message(chat.id, catalog.tr(lang, "bot-is-private"))
+            .await?;
+    }
+    if leave {
+        bot.leave_chat(chat.id).await?;
+        log::info!("Left unauthorized chat [{}]", chat.id);
+    }
+    if policy.notify_owners {
+        notify_owners(bot, auth, catalog, storage, chat, leave).await;
+    }
+    Ok(())
+}
+
+async fn notify_owners(
+    bot: &Bot,
+    auth: &Auth,
+    catalog: &Catalog,
+    storage: &Storage,
+    chat: &Chat,
+    has_left: bool,
+) {
+    let chat_id = chat.id.to_string();
+    let key = match has_left {
+        true => "owners-left-chat",
+        false => "owners-unauthorized-chat",
+    };
+    for owner in auth.owners() {
+        let lang = storage
+            .user_preferences()
+            .get(*owner)
+            .ok()
+            .and_then(|prefs| prefs.ui_language)
+            .as_deref()
+            .and_then(Language::parse_code)
+            .unwrap_or(Language::English);
+        let text = catalog.tr_args(
+            lang,
+            key,
+            &[("chat_id", &chat_id), ("name", chat_name(chat))],
+        );
+        if let Err(err) = bot.send_message(*owner, text).await {
+            log::warn!("Cannot notify owner [{}]: {err}", owner);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use teloxide::types::UserId;
+
+    #[test]
+    fn pending_invites_keep_the_bot_only_in_groups_it_just_joined() {
+        let storage = Storage::in_memory().unwrap();
+        let config = Config::new(
+            String::new().into(),
+            String::new().into(),
+            Vec::new(),
+            None,
+            None,
+            false,
+        );
+        let auth = Auth::new(&config, storage.clone()).unwrap();
+        auth.record_join(ChatId(-1));
+        assert!(!awaits_activation(&auth, &storage, ChatId(-1)).unwrap());
+
+        let ttl = Duration::from_secs(60 * 60);
+        storage.invites().create("CODE", UserId(1), ttl).unwrap();
+        assert!(awaits_activation(&auth, &storage, ChatId(-1)).unwrap());
+        assert!(!awaits_activation(&auth, &storage, ChatId(-2)).unwrap());
+    }
+}
@@ -0,0 +1,129 @@
+use chrono::NaiveDate;
+use serde::Serialize;
+use std::collections::HashMap;
+use teloxide::types::ChatId;
+
+use crate::i18n::Catalog;
+use crate::language::Language;
+use crate::storage::Storage;
+use crate::Auth;
+
+/// Characters translated in a month with the estimated cost, per provider
+/// and chat. Costs use the configured price per million characters.
+#[derive(Debug, Serialize)]
+pub struct UsageReport {
+    pub month: String,
+    pub providers: Vec<ProviderUsage>,
+    pub total_chars: u64,
+    pub total_cost: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProviderUsage {
+    pub provider: String,
+    pub chars: u64,
+    pub cost: f64,
+    pub chats: Vec<ChatUsage>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChatUsage {
+    pub chat_id: i64,
+    pub chars: u64,
+    pub cost: f64,
+}
+
+/// Checks that `text` is a month like `2022-11`.
+pub fn parse_month(text: &str) -> Option<String> {
+    NaiveDate::parse_from_str(&format!("{text}-01"), "%Y-%m-%d")
+        .ok()
+        .map(|date| date.format("%Y-%m").to_string())
+}
+
+impl UsageReport {
+    pub fn for_month(
+        storage: &Storage,
+        prices: &HashMap<String, f64>,
+        month: &str,
+    ) -> crate::Result<Self> {
+        let mut providers: Vec<ProviderUsage> = vec![];
+        for usage in storage.usage().by_provider_and_chat(month)? {
+            let price =
+                prices.get(&usage.provider).copied().unwrap_or_else(|| {
+                    log::warn!("No price configured for {}", usage.provider);
+                    0.0
+                });
+            let cost = usage.chars as f64 * price / 1_000_000.0;
+            let chat = ChatUsage {
+                chat_id: usage.chat_id,
+                chars: usage.chars,
+                cost,
+            };
+            match providers.last_mut() {
+                Some(provider) if provider.provider == usage.provider => {
+                    provider.chars += chat.chars;
+                    provider.cost += chat.cost;
+                    provider.chats.push(chat);
+                }
+                _ => providers.push(ProviderUsage {
+                    provider: usage.provider,
+                    chars: chat.chars,
+                    cost: chat.cost,
+                    chats: vec![chat],
+                }),
+            }
+        }
+
+        Ok(Self {
+            month: month.to_string(),
+            total_chars: providers.iter().map(|p| p.chars).sum(),
+            total_cost: providers.iter().map(|p| p.cost).sum(),
+            providers,
+        })
+    }
+
+    /// The report as a chat message, naming the allowed chats.
+    pub fn to_text(
+        &self,
+        catalog: &Catalog,
+        lang: Language,
+        auth: &Auth,
+    ) -> String {
+        let month = [("month", self.month.as_str())];
+        if self.providers.is_empty() {
+            return catalog.tr_args(lang, "usage-empty", &month);
+        }
+
+        let line = |name: &str, chars: u64, cost: f64| {
+            catalog.tr_args(
+                lang,
+                "usage-line",
+                &[
+                    ("name", name),
+                    ("chars", &chars.to_string()),
+                    ("cost", &format!("{cost:.2}")),
+                ],
+            )
+        };
+        let mut lines = vec![catalog.tr_args(lang, "usage-header", &month)];
+        for provider in &self.providers {
+            lines.push(line(&provider.provider, provider.chars, provider.cost));
+            for chat in &provider.chats {
+                let name = match auth.get_chat_name(&ChatId(chat.chat_id)) {
+                    Some(name) => format!("{} {}", chat.chat_id, name),
+                    None => chat.chat_id.to_string(),
+                };
+                lines.push(format!("  {}", line(&name, chat.chars, chat.cost)));
+            }
+        }
+        lines.push(catalog.tr_args(
+            lang,
+            "usage-total",
+            &[
+                ("chars", &self.total_chars.to_string()),
+                ("cost", &format!("{:.2}", self.total_cost)),
+            ],
+        ));
+        lines.join("\n")
+    }
+}
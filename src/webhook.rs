@@ -1,27 +1,50 @@
+use axum::extract::Extension;
+use axum::http::{HeaderMap, StatusCode};
 use axum::response::IntoResponse;
-use axum::routing::get;
-use std::net::SocketAddr;
 use std::sync::Arc;
 use teloxide::dispatching::update_listeners::StatefulListener;
 use teloxide::prelude::*;
 use teloxide::stop::{mk_stop_token, StopToken};
+use teloxide::types::{InputFile, Update};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
 
-use crate::Config;
+use crate::config::SecretString;
+use crate::health::Health;
+use crate::server::spawn_server;
+use crate::storage::Storage;
+use crate::tls::is_self_signed;
+use crate::Reloader;
 
 // Original implementation:
 // https://github.com/zamazan4ik/npaperbot-telegram/blob/2eb14ec7121153768deb0d3763451ae58fa78572/src/webhook.rs
 
+/// The header Telegram sends the webhook secret token in.
+const SECRET_TOKEN_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+
+/// Passes the updates from the webhook on to the dispatcher.
+type UpdateSender = mpsc::Sender<Result<Update, String>>;
+
+/// Queues an update for the dispatcher. Answers 400 if it can't be parsed,
+/// and 503 if the queue is full, for Telegram to retry it later.
 async fn telegram_request(
+    Extension(secret_token): Extension<Arc<SecretString>>,
+    Extension(tx): Extension<UpdateSender>,
+    headers: HeaderMap,
     input: String,
-    tx: axum::extract::Extension<
-        tokio::sync::mpsc::UnboundedSender<
-            Result<teloxide::types::Update, String>,
-        >,
-    >,
 ) -> impl IntoResponse {
-    log::info!("Webhook input: {}", input);
-    let try_parse = match serde_json::from_str(&input) {
-        Ok(update) => Ok(update),
+    let given = headers
+        .get(SECRET_TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok());
+    if !given.is_some_and(|given| secret_token.matches(given)) {
+        log::warn!("Rejected a webhook request without the secret token");
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    log::debug!("Webhook input: {}", input);
+    let update = match serde_json::from_str(&input) {
+        Ok(update) => update,
         Err(error) => {
             log::error!(
                 "Cannot parse an update.\nError: {:?}\nValue: {}\n\
@@ -30,77 +53,96 @@ async fn telegram_request(
                 error,
                 input
             );
-            Err(error)
+            return StatusCode::BAD_REQUEST;
         }
     };
-    if let Ok(update) = try_parse {
-        tx.send(Ok(update))
-            .expect("Cannot send an incoming update from the webhook")
+    match tx.try_send(Ok(update)) {
+        Ok(()) => StatusCode::OK,
+        Err(TrySendError::Full(_)) => {
+            log::warn!("The update queue is full, Telegram will retry later");
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+        Err(TrySendError::Closed(_)) => {
+            log::warn!("Updates are no longer being handled");
+            StatusCode::SERVICE_UNAVAILABLE
+        }
     }
-
-    axum::http::StatusCode::OK
 }
 
+/// Serves the webhook, then registers it with Telegram. The server starts
+/// first so that it's there for the first update, and for the probes.
+/// Returns the update listener and the task of the server, or why the
+/// server can't listen or the webhook can't be registered.
 pub async fn webhook(
-    config: Arc<Config>,
+    reloader: Arc<Reloader>,
     bot: Bot,
-) -> impl teloxide::dispatching::update_listeners::UpdateListener<Err = String>
-{
-    let webhook = &config
-        .webhook
-        .clone()
-        .expect("Webhook config is expected when setting WEBHOOK_MODE=true");
-
-    bot.set_webhook(webhook.url.parse().unwrap())
-        .await
-        .expect("Cannot setup a webhook");
+    storage: Storage,
+    health: Arc<Health>,
+) -> crate::Result<(
+    impl teloxide::dispatching::update_listeners::UpdateListener<Err = String>,
+    JoinHandle<crate::Result<()>>,
+)> {
+    let config = reloader.state().config.clone();
+    let webhook = config.webhook.clone().ok_or_else(|| crate::AppError {
+        msg: "Webhook mode needs DOMAIN_HOST to be set".to_string(),
+    })?;
 
-    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let (tx, rx) = mpsc::channel(config.webhook_queue_capacity);
 
-    let app = axum::Router::new()
-        .route("/", get(root))
+    let routes = axum::Router::new()
         .route(webhook.path.as_str(), axum::routing::post(telegram_request))
         .layer(
             tower::ServiceBuilder::new()
-                .layer(tower_http::trace::TraceLayer::new_for_http())
                 .layer(tower_http::add_extension::AddExtensionLayer::new(tx))
+                .layer(tower_http::add_extension::AddExtensionLayer::new(
+                    Arc::new(webhook.secret_token.clone()),
+                ))
                 .into_inner(),
         );
 
-    let server_address = SocketAddr::from((config.bind_address, config.port));
-    tracing::debug!("server listening on {}", server_address);
+    // The dispatcher stops the listener when it shuts down, which stops the
+    // server from accepting requests. Once the server is done, the update
+    // stream ends and the dispatcher finishes the updates it has.
+    let (stop_token, stop_flag) = mk_stop_token();
+    let server =
+        spawn_server(routes, reloader, storage, health.clone(), stop_flag)
+            .await?;
 
-    tokio::spawn(async move {
-        axum::Server::bind(&server_address)
-            .serve(app.into_make_service())
-            .with_graceful_shutdown(shutdown_signal())
-            .await
-            .expect("Axum server error")
-    });
+    let url = webhook.url.parse().map_err(|err| crate::AppError {
+        msg: format!("Invalid webhook URL: {err}"),
+    })?;
+    let mut set_webhook = bot
+        .set_webhook(url)
+        .secret_token(webhook.secret_token.expose_secret());
+    if let Some(tls) = &config.tls {
+        match is_self_signed(&tls.cert_path) {
+            Ok(true) => {
+                log::info!("Uploading the self-signed certificate to Telegram");
+                set_webhook =
+                    set_webhook.certificate(InputFile::file(&tls.cert_path));
+            }
+            Ok(false) => {}
+            Err(err) => log::warn!(
+                "Cannot tell whether the certificate is self-signed: {err}"
+            ),
+        }
+    }
+    if let Err(err) = set_webhook.await {
+        stop_token.stop();
+        return Err(err.into());
+    }
+    health.set_webhook_registered(true);
 
-    let stream = tokio_stream::wrappers::UnboundedReceiverStream::new(rx);
+    let stream = tokio_stream::wrappers::ReceiverStream::new(rx);
 
     fn streamf<S, T>(state: &mut (S, T)) -> &mut S {
         &mut state.0
     }
 
-    let (stop_token, _stop_flag) = mk_stop_token();
-    StatefulListener::new(
+    let listener = StatefulListener::new(
         (stream, stop_token),
         streamf,
         |state: &mut (_, StopToken)| state.1.clone(),
-    )
-}
-
-/// Tokio signal handler that will wait for a user to press CTRL+C.
-/// We use this in our hyper `Server` method `with_graceful_shutdown`.
-async fn shutdown_signal() {
-    tokio::signal::ctrl_c()
-        .await
-        .expect("Expect shutdown signal handler");
-    println!("signal shutdown");
-}
-
-async fn root() -> &'static str {
-    "ok"
+    );
+    Ok((listener, server))
 }